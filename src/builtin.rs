@@ -3,7 +3,7 @@ use indexmap::IndexMap;
 use std::collections::HashMap;
 
 use crate::{
-    data::{Function, SValue, Value},
+    data::{BoxedRest, Function, List, SValue, Value},
     error,
 };
 
@@ -17,6 +17,14 @@ pub fn builtin_functions() -> HashMap<String, SValue> {
             implementation: Box::new(json),
         },
     );
+    functions.insert(
+        "to_json".to_string(),
+        Function {
+            name: "to_json".to_string(),
+            arities: vec![1, 2],
+            implementation: Box::new(to_json),
+        },
+    );
     functions.insert(
         "get".to_string(),
         Function {
@@ -25,130 +33,3137 @@ pub fn builtin_functions() -> HashMap<String, SValue> {
             implementation: Box::new(get),
         },
     );
-    functions.insert(
-        "assoc".to_string(),
-        Function {
-            name: "assoc".to_string(),
-            arities: vec![3],
-            implementation: Box::new(assoc),
-        },
+    functions.insert(
+        "query".to_string(),
+        Function {
+            name: "query".to_string(),
+            arities: vec![2],
+            implementation: Box::new(query),
+        },
+    );
+    functions.insert(
+        "assoc".to_string(),
+        Function {
+            name: "assoc".to_string(),
+            arities: vec![3],
+            implementation: Box::new(assoc),
+        },
+    );
+    functions.insert(
+        "update".to_string(),
+        Function {
+            name: "update".to_string(),
+            arities: vec![3],
+            implementation: Box::new(update),
+        },
+    );
+    functions.insert(
+        "type".to_string(),
+        Function {
+            name: "type".to_string(),
+            arities: vec![1],
+            implementation: Box::new(type_name),
+        },
+    );
+    functions.insert(
+        "has".to_string(),
+        Function {
+            name: "has".to_string(),
+            arities: vec![2],
+            implementation: Box::new(has),
+        },
+    );
+    functions.insert(
+        "dissoc".to_string(),
+        Function {
+            name: "dissoc".to_string(),
+            arities: vec![2],
+            implementation: Box::new(dissoc),
+        },
+    );
+    functions.insert(
+        "deep_equal_ordered".to_string(),
+        Function {
+            name: "deep_equal_ordered".to_string(),
+            arities: vec![2],
+            implementation: Box::new(deep_equal_ordered),
+        },
+    );
+    functions.insert(
+        "merge".to_string(),
+        Function {
+            name: "merge".to_string(),
+            arities: vec![2],
+            implementation: Box::new(merge),
+        },
+    );
+    functions.insert(
+        "abs".to_string(),
+        Function {
+            name: "abs".to_string(),
+            arities: vec![1],
+            implementation: Box::new(abs),
+        },
+    );
+    functions.insert(
+        "floor".to_string(),
+        Function {
+            name: "floor".to_string(),
+            arities: vec![1],
+            implementation: Box::new(floor),
+        },
+    );
+    functions.insert(
+        "ceil".to_string(),
+        Function {
+            name: "ceil".to_string(),
+            arities: vec![1],
+            implementation: Box::new(ceil),
+        },
+    );
+    functions.insert(
+        "round".to_string(),
+        Function {
+            name: "round".to_string(),
+            arities: vec![1],
+            implementation: Box::new(round),
+        },
+    );
+    functions.insert(
+        "sqrt".to_string(),
+        Function {
+            name: "sqrt".to_string(),
+            arities: vec![1],
+            implementation: Box::new(sqrt),
+        },
+    );
+    functions.insert(
+        "sum".to_string(),
+        Function {
+            name: "sum".to_string(),
+            arities: vec![1],
+            implementation: Box::new(sum),
+        },
+    );
+    functions.insert(
+        "min".to_string(),
+        Function {
+            name: "min".to_string(),
+            arities: vec![1],
+            implementation: Box::new(min),
+        },
+    );
+    functions.insert(
+        "max".to_string(),
+        Function {
+            name: "max".to_string(),
+            arities: vec![1],
+            implementation: Box::new(max),
+        },
+    );
+    functions.insert(
+        "sum_by".to_string(),
+        Function {
+            name: "sum_by".to_string(),
+            arities: vec![2],
+            implementation: Box::new(sum_by),
+        },
+    );
+    functions.insert(
+        "avg_by".to_string(),
+        Function {
+            name: "avg_by".to_string(),
+            arities: vec![2],
+            implementation: Box::new(avg_by),
+        },
+    );
+    functions.insert(
+        "sorted".to_string(),
+        Function {
+            name: "sorted".to_string(),
+            arities: vec![1],
+            implementation: Box::new(sorted),
+        },
+    );
+    functions.insert(
+        "reverse".to_string(),
+        Function {
+            name: "reverse".to_string(),
+            arities: vec![1],
+            implementation: Box::new(reverse),
+        },
+    );
+    functions.insert(
+        "only".to_string(),
+        Function {
+            name: "only".to_string(),
+            arities: vec![1],
+            implementation: Box::new(only),
+        },
+    );
+    functions.insert(
+        "map".to_string(),
+        Function {
+            name: "map".to_string(),
+            arities: vec![2],
+            implementation: Box::new(map),
+        },
+    );
+    functions.insert(
+        "first_key".to_string(),
+        Function {
+            name: "first_key".to_string(),
+            arities: vec![1],
+            implementation: Box::new(first_key),
+        },
+    );
+    functions.insert(
+        "last_key".to_string(),
+        Function {
+            name: "last_key".to_string(),
+            arities: vec![1],
+            implementation: Box::new(last_key),
+        },
+    );
+    functions.insert(
+        "json5".to_string(),
+        Function {
+            name: "json5".to_string(),
+            arities: vec![1],
+            implementation: Box::new(json5),
+        },
+    );
+    functions.insert(
+        "yaml".to_string(),
+        Function {
+            name: "yaml".to_string(),
+            arities: vec![1],
+            implementation: Box::new(yaml),
+        },
+    );
+    functions.insert(
+        "from_csv".to_string(),
+        Function {
+            name: "from_csv".to_string(),
+            arities: vec![1],
+            implementation: Box::new(from_csv),
+        },
+    );
+    functions.insert(
+        "from_ndjson".to_string(),
+        Function {
+            name: "from_ndjson".to_string(),
+            arities: vec![1],
+            implementation: Box::new(from_ndjson),
+        },
+    );
+    functions.insert(
+        "group_by".to_string(),
+        Function {
+            name: "group_by".to_string(),
+            arities: vec![2],
+            implementation: Box::new(group_by),
+        },
+    );
+    functions.insert(
+        "lazy_group_by".to_string(),
+        Function {
+            name: "lazy_group_by".to_string(),
+            arities: vec![2],
+            implementation: Box::new(lazy_group_by),
+        },
+    );
+    functions.insert(
+        "flatten_dict".to_string(),
+        Function {
+            name: "flatten_dict".to_string(),
+            arities: vec![1],
+            implementation: Box::new(flatten_dict),
+        },
+    );
+    functions.insert(
+        "unflatten_dict".to_string(),
+        Function {
+            name: "unflatten_dict".to_string(),
+            arities: vec![1],
+            implementation: Box::new(unflatten_dict),
+        },
+    );
+
+    functions.insert(
+        "zip_to_dict".to_string(),
+        Function {
+            name: "zip_to_dict".to_string(),
+            arities: vec![2],
+            implementation: Box::new(zip_to_dict),
+        },
+    );
+
+    functions.insert(
+        "to_markdown_table".to_string(),
+        Function {
+            name: "to_markdown_table".to_string(),
+            arities: vec![1],
+            implementation: Box::new(to_markdown_table),
+        },
+    );
+
+    functions.insert(
+        "skip_errors".to_string(),
+        Function {
+            name: "skip_errors".to_string(),
+            arities: vec![1],
+            implementation: Box::new(skip_errors),
+        },
+    );
+
+    functions.insert(
+        "describe".to_string(),
+        Function {
+            name: "describe".to_string(),
+            arities: vec![1],
+            implementation: Box::new(describe),
+        },
+    );
+
+    functions.insert(
+        "cycle".to_string(),
+        Function {
+            name: "cycle".to_string(),
+            arities: vec![1],
+            implementation: Box::new(cycle),
+        },
+    );
+    functions.insert(
+        "flatten".to_string(),
+        Function {
+            name: "flatten".to_string(),
+            arities: vec![1],
+            implementation: Box::new(flatten),
+        },
+    );
+    functions.insert(
+        "concat".to_string(),
+        Function {
+            name: "concat".to_string(),
+            arities: (1..=MAX_CONCAT).collect(),
+            implementation: Box::new(concat),
+        },
+    );
+    functions.insert(
+        "take".to_string(),
+        Function {
+            name: "take".to_string(),
+            arities: vec![2],
+            implementation: Box::new(take),
+        },
+    );
+    functions.insert(
+        "url_encode".to_string(),
+        Function {
+            name: "url_encode".to_string(),
+            arities: vec![1],
+            implementation: Box::new(url_encode),
+        },
+    );
+    functions.insert(
+        "url_decode".to_string(),
+        Function {
+            name: "url_decode".to_string(),
+            arities: vec![1],
+            implementation: Box::new(url_decode),
+        },
+    );
+    functions.insert(
+        "query_to_dict".to_string(),
+        Function {
+            name: "query_to_dict".to_string(),
+            arities: vec![1],
+            implementation: Box::new(query_to_dict),
+        },
+    );
+    functions.insert(
+        "dict_to_query".to_string(),
+        Function {
+            name: "dict_to_query".to_string(),
+            arities: vec![1],
+            implementation: Box::new(dict_to_query),
+        },
+    );
+    functions.insert(
+        "hash".to_string(),
+        Function {
+            name: "hash".to_string(),
+            arities: vec![2],
+            implementation: Box::new(hash),
+        },
+    );
+    functions.insert(
+        "filter_dict".to_string(),
+        Function {
+            name: "filter_dict".to_string(),
+            arities: vec![2],
+            implementation: Box::new(filter_dict),
+        },
+    );
+    functions.insert(
+        "render".to_string(),
+        Function {
+            name: "render".to_string(),
+            arities: vec![2],
+            implementation: Box::new(render),
+        },
+    );
+    functions.insert(
+        "values".to_string(),
+        Function {
+            name: "values".to_string(),
+            arities: vec![1],
+            implementation: Box::new(values),
+        },
+    );
+    functions.insert(
+        "json_schema_validate".to_string(),
+        Function {
+            name: "json_schema_validate".to_string(),
+            arities: vec![2],
+            implementation: Box::new(json_schema_validate),
+        },
+    );
+    functions.insert(
+        "parse_date".to_string(),
+        Function {
+            name: "parse_date".to_string(),
+            arities: vec![2],
+            implementation: Box::new(parse_date),
+        },
+    );
+    functions.insert(
+        "format_date".to_string(),
+        Function {
+            name: "format_date".to_string(),
+            arities: vec![2],
+            implementation: Box::new(format_date),
+        },
+    );
+    functions.insert(
+        "date_diff".to_string(),
+        Function {
+            name: "date_diff".to_string(),
+            arities: vec![3],
+            implementation: Box::new(date_diff),
+        },
+    );
+    functions.insert(
+        "now".to_string(),
+        Function {
+            name: "now".to_string(),
+            arities: vec![0],
+            implementation: Box::new(now),
+        },
+    );
+    functions.insert(
+        "range".to_string(),
+        Function {
+            name: "range".to_string(),
+            arities: vec![1, 2],
+            implementation: Box::new(range),
+        },
+    );
+    functions.insert(
+        "sample_n".to_string(),
+        Function {
+            name: "sample_n".to_string(),
+            arities: vec![2],
+            implementation: Box::new(sample_n),
+        },
+    );
+    functions.insert(
+        "sort".to_string(),
+        Function {
+            name: "sort".to_string(),
+            arities: vec![1, 2],
+            implementation: Box::new(sort),
+        },
+    );
+    functions.insert(
+        "len".to_string(),
+        Function {
+            name: "len".to_string(),
+            arities: vec![1],
+            implementation: Box::new(len),
+        },
+    );
+    functions.insert(
+        "entries".to_string(),
+        Function {
+            name: "entries".to_string(),
+            arities: vec![1],
+            implementation: Box::new(entries),
+        },
+    );
+    functions.insert(
+        "enumerate".to_string(),
+        Function {
+            name: "enumerate".to_string(),
+            arities: vec![1],
+            implementation: Box::new(enumerate),
+        },
+    );
+    functions.insert(
+        "zip".to_string(),
+        Function {
+            name: "zip".to_string(),
+            arities: vec![2],
+            implementation: Box::new(zip),
+        },
+    );
+    functions.insert(
+        "keys".to_string(),
+        Function {
+            name: "keys".to_string(),
+            arities: vec![1],
+            implementation: Box::new(keys),
+        },
+    );
+    functions.insert(
+        "reduce".to_string(),
+        Function {
+            name: "reduce".to_string(),
+            arities: vec![3],
+            implementation: Box::new(reduce),
+        },
+    );
+    functions.insert(
+        "filter".to_string(),
+        Function {
+            name: "filter".to_string(),
+            arities: vec![2],
+            implementation: Box::new(filter),
+        },
+    );
+    functions.insert(
+        "first".to_string(),
+        Function {
+            name: "first".to_string(),
+            arities: vec![1],
+            implementation: Box::new(first),
+        },
+    );
+    functions.insert(
+        "last".to_string(),
+        Function {
+            name: "last".to_string(),
+            arities: vec![1],
+            implementation: Box::new(last),
+        },
+    );
+    functions.insert(
+        "first_where".to_string(),
+        Function {
+            name: "first_where".to_string(),
+            arities: vec![2],
+            implementation: Box::new(first_where),
+        },
+    );
+    functions.insert(
+        "last_where".to_string(),
+        Function {
+            name: "last_where".to_string(),
+            arities: vec![2],
+            implementation: Box::new(last_where),
+        },
+    );
+    functions.insert(
+        "json_escape".to_string(),
+        Function {
+            name: "json_escape".to_string(),
+            arities: vec![1],
+            implementation: Box::new(json_escape),
+        },
+    );
+    functions.insert(
+        "json_unescape".to_string(),
+        Function {
+            name: "json_unescape".to_string(),
+            arities: vec![1],
+            implementation: Box::new(json_unescape),
+        },
+    );
+    functions.insert(
+        "upper".to_string(),
+        Function {
+            name: "upper".to_string(),
+            arities: vec![1],
+            implementation: Box::new(upper),
+        },
+    );
+    functions.insert(
+        "lower".to_string(),
+        Function {
+            name: "lower".to_string(),
+            arities: vec![1],
+            implementation: Box::new(lower),
+        },
+    );
+    functions.insert(
+        "trim".to_string(),
+        Function {
+            name: "trim".to_string(),
+            arities: vec![1],
+            implementation: Box::new(trim),
+        },
+    );
+    functions.insert(
+        "replace".to_string(),
+        Function {
+            name: "replace".to_string(),
+            arities: vec![3],
+            implementation: Box::new(replace),
+        },
+    );
+    functions.insert(
+        "unique".to_string(),
+        Function {
+            name: "unique".to_string(),
+            arities: vec![1],
+            implementation: Box::new(unique),
+        },
+    );
+    functions.insert(
+        "distinct_count".to_string(),
+        Function {
+            name: "distinct_count".to_string(),
+            arities: vec![2],
+            implementation: Box::new(distinct_count),
+        },
+    );
+    #[cfg(feature = "threadsafe")]
+    functions.insert(
+        "pmap".to_string(),
+        Function {
+            name: "pmap".to_string(),
+            arities: vec![2],
+            implementation: Box::new(pmap),
+        },
+    );
+
+    functions
+        .into_iter()
+        .map(|(k, v)| (k, SValue::new(Value::Function(v))))
+        .collect()
+}
+
+fn get(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "get function expects exactly two arguments"
+    );
+    let key = args.remove(1);
+    let container = args.remove(0);
+
+    match &*key {
+        Value::String(s) => {
+            let Value::Dict(dict) = &*container else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "get function expects a dict as the first argument, got {:?}",
+                    container
+                )));
+            };
+            let value = dict
+                .elements
+                .borrow()
+                .get(s)
+                .cloned()
+                .unwrap_or_else(|| SValue::new(Value::Null));
+            Ok(value)
+        }
+        Value::Int(n) => {
+            let Value::List(list) = &*container else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "get function expects a list as the first argument, got {:?}",
+                    container
+                )));
+            };
+            let element = match usize::try_from(*n) {
+                Ok(i) => list.get(i)?,
+                Err(_) => None,
+            };
+            element.ok_or_else(|| {
+                error::Error::BuiltinFunctionError(format!("index out of bounds: {}", n))
+            })
+        }
+        _ => Err(error::Error::BuiltinFunctionError(
+            "get function expects a string or an integer as the second argument".to_string(),
+        )),
+    }
+}
+
+/// Follow a path of keys and indices like `get`. A `"*"` segment follows the rest of the path
+/// from every element of a list or value of a dict, and the results are collected into a flat list.
+fn query(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "query function expects exactly two arguments"
+    );
+    let path = args.remove(1);
+    let container = args.remove(0);
+
+    let Value::List(path) = &*path else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "query function expects a list as the second argument, got {path}"
+        )));
+    };
+    path.realize_all()?;
+    let path = path.elements.borrow().clone();
+
+    fn query_into(value: SValue, path: &[SValue], result: &mut Vec<SValue>) -> error::Result<()> {
+        let Some((segment, rest)) = path.split_first() else {
+            result.push(value);
+            return Ok(());
+        };
+        if segment.as_string() != Some("*") {
+            return query_into(get(vec![value, segment.clone()])?, rest, result);
+        }
+        match &*value {
+            Value::List(_) => {
+                for elem in List::into_iter(value.clone()) {
+                    query_into(elem?, rest, result)?;
+                }
+            }
+            Value::Dict(d) => {
+                d.realize_all()?;
+                let values: Vec<_> = d.elements.borrow().values().cloned().collect();
+                for v in values {
+                    query_into(v, rest, result)?;
+                }
+            }
+            _ => {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "query function can only use * on a list or a dict, got {value}"
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    let mut result = vec![];
+    query_into(container, &path, &mut result)?;
+    if path.iter().any(|segment| segment.as_string() == Some("*")) {
+        Ok(SValue::new(Value::List(List {
+            elements: result.into(),
+            rest: None.into(),
+        })))
+    } else {
+        Ok(result.remove(0))
+    }
+}
+
+/// Replacing an existing key keeps its position, a new key is appended, see [`crate::data::Dict`]
+fn assoc(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 3,
+        "assoc function expects exactly three arguments"
+    );
+    let value = args.remove(2);
+    let key = args.remove(1);
+    let container = args.remove(0);
+
+    match &*key {
+        Value::String(s) => {
+            let Value::Dict(dict) = &*container else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "assoc function expects a dict as the first argument, got {container}",
+                )));
+            };
+            dict.realize_all()?;
+            // lazy_rest is None, so we can just copy the elements
+            let mut elements = dict.elements.borrow().clone();
+            elements.insert(s.clone(), value);
+            Ok(SValue::new(Value::Dict(crate::data::Dict {
+                elements: elements.into(),
+                rest: None.into(),
+            })))
+        }
+        Value::Int(n) => {
+            let Value::List(list) = &*container else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "assoc function expects a list as the first argument, got {container}",
+                )));
+            };
+            list.realize_all()?;
+            let mut elements = list.elements.borrow().clone();
+            if let Some(e) = elements.get_mut(*n as usize) {
+                *e = value;
+            } else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "index out of bounds: {n}",
+                )));
+            }
+            Ok(SValue::new(Value::List(crate::data::List {
+                elements: elements.into(),
+                rest: None.into(),
+            })))
+        }
+        _ => Err(error::Error::BuiltinFunctionError(
+            "assoc function expects a string or an integer as the second argument".to_string(),
+        )),
+    }
+}
+
+/// Whether the dict has the key or the index is in bounds of the list. Unlike `get` a missing key
+/// is not an error. A lazy dict is realized until the key is found (or entirely if it's absent),
+/// a lazy list is realized up to the index.
+fn has(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "has function expects exactly two arguments"
+    );
+    let key = args.remove(1);
+    let container = args.remove(0);
+
+    match &*key {
+        Value::String(s) => {
+            let Value::Dict(dict) = &*container else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "has function expects a dict as the first argument, got {container}",
+                )));
+            };
+            Ok(SValue::new(Value::Bool(dict.get(s)?.is_some())))
+        }
+        Value::Int(n) => {
+            let Value::List(list) = &*container else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "has function expects a list as the first argument, got {container}",
+                )));
+            };
+            let present = match usize::try_from(*n) {
+                Ok(i) => list.get(i)?.is_some(),
+                Err(_) => false,
+            };
+            Ok(SValue::new(Value::Bool(present)))
+        }
+        _ => Err(error::Error::BuiltinFunctionError(
+            "has function expects a string or an integer as the second argument".to_string(),
+        )),
+    }
+}
+
+/// Replace the value at the key or index with the function applied to it, following the rules of
+/// `get` and `assoc`. A missing key is updated from null.
+fn update(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 3,
+        "update function expects exactly three arguments"
+    );
+    let func = args.remove(2);
+    let key = args.remove(1);
+    let container = args.remove(0);
+
+    let Value::Function(f) = &*func else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "update function expects a function as the third argument, got {func}",
+        )));
+    };
+    // `get` only looks at the realized entries of a dict
+    if let Value::Dict(d) = &*container {
+        d.realize_all()?;
+    }
+    let current = get(vec![container.clone(), key.clone()])?;
+    let updated = (f.implementation)(vec![current])?;
+    assoc(vec![container, key, updated])
+}
+
+fn dissoc(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "dissoc function expects exactly two arguments"
+    );
+    let key = args.remove(1);
+    let container = args.remove(0);
+
+    match &*key {
+        Value::String(s) => {
+            let Value::Dict(dict) = &*container else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "dissoc function expects a dict as the first argument, got {container}",
+                )));
+            };
+            dict.realize_all()?;
+            let mut elements = dict.elements.borrow().clone();
+            // Keep the order of the other keys
+            elements.shift_remove(s);
+            Ok(SValue::new(Value::Dict(crate::data::Dict {
+                elements: elements.into(),
+                rest: None.into(),
+            })))
+        }
+        Value::Int(n) => {
+            let Value::List(list) = &*container else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "dissoc function expects a list as the first argument, got {container}",
+                )));
+            };
+            list.realize_all()?;
+            let mut elements = list.elements.borrow().clone();
+            if (*n as usize) < elements.len() {
+                elements.remove(*n as usize);
+            } else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "index out of bounds: {n}",
+                )));
+            }
+            Ok(SValue::new(Value::List(crate::data::List {
+                elements: elements.into(),
+                rest: None.into(),
+            })))
+        }
+        _ => Err(error::Error::BuiltinFunctionError(
+            "dissoc function expects a string or an integer as the second argument".to_string(),
+        )),
+    }
+}
+
+/// Like `==`, except that dicts are only equal when their keys are in the same order. `==` ignores
+/// key order, `{"a": 1, "b": 2} == {"b": 2, "a": 1}` is true.
+fn deep_equal_ordered(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "deep_equal_ordered function expects exactly two arguments"
+    );
+    let y = args.remove(1);
+    let x = args.remove(0);
+    x.realize()?;
+    y.realize()?;
+    Ok(SValue::new(Value::Bool(equal_realized(&x, &y, true))))
+}
+
+/// Numbers are compared by value at any depth, and dict keys must be in the same order only with
+/// `key_order`. Both values must be realized.
+fn equal_realized(x: &Value, y: &Value, key_order: bool) -> bool {
+    if let (Some(x), Some(y)) = (x.as_number(), y.as_number()) {
+        return x == y;
+    }
+    match (x, y) {
+        (Value::List(x), Value::List(y)) => {
+            let (x, y) = (x.elements.borrow(), y.elements.borrow());
+            x.len() == y.len()
+                && x.iter()
+                    .zip(y.iter())
+                    .all(|(x, y)| equal_realized(x, y, key_order))
+        }
+        (Value::Dict(x), Value::Dict(y)) if key_order => {
+            let (x, y) = (x.elements.borrow(), y.elements.borrow());
+            x.len() == y.len()
+                && x.iter()
+                    .zip(y.iter())
+                    .all(|((kx, x), (ky, y))| kx == ky && equal_realized(x, y, key_order))
+        }
+        (Value::Dict(x), Value::Dict(y)) => {
+            let (x, y) = (x.elements.borrow(), y.elements.borrow());
+            x.len() == y.len()
+                && x.iter()
+                    .all(|(k, x)| y.get(k).is_some_and(|y| equal_realized(x, y, key_order)))
+        }
+        _ => x == y,
+    }
+}
+
+/// The entries of both dicts, the second one's values winning. Keys keep the first dict's order,
+/// followed by the keys only the second one has.
+fn merge(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "merge function expects exactly two arguments"
+    );
+    let second = args.remove(1);
+    let first = args.remove(0);
+
+    let (Value::Dict(first), Value::Dict(second)) = (&*first, &*second) else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "merge function expects two dicts, got {first} and {second}",
+        )));
+    };
+    first.realize_all()?;
+    second.realize_all()?;
+    let mut elements = first.elements.borrow().clone();
+    for (k, v) in second.elements.borrow().iter() {
+        elements.insert(k.clone(), v.clone());
+    }
+    Ok(SValue::new(Value::Dict(crate::data::Dict {
+        elements: elements.into(),
+        rest: None.into(),
+    })))
+}
+
+/// An int when all the keys are ints and the total fits, like [`sum`]
+fn sum_by(args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "sum_by function expects exactly two arguments"
+    );
+    Ok(total(&aggregate_by("sum_by", args)?))
+}
+
+fn avg_by(args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "avg_by function expects exactly two arguments"
+    );
+    let keys = aggregate_by("avg_by", args)?;
+    if keys.is_empty() {
+        return Err(error::Error::BuiltinFunctionError(
+            "avg_by function expects a non-empty list".to_string(),
+        ));
+    }
+    let sum: f64 = keys.iter().filter_map(|n| n.as_number()).sum();
+    Ok(SValue::new(Value::Float(sum / keys.len() as f64)))
+}
+
+fn abs(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(args.len() == 1, "abs function expects exactly one argument");
+    let n = args.remove(0);
+    match &*n {
+        Value::Int(i) => Ok(SValue::new(match i.checked_abs() {
+            Some(i) => Value::Int(i),
+            None => Value::Float(-(*i as f64)),
+        })),
+        Value::BigInt(digits) => Ok(SValue::new(Value::BigInt(
+            digits.trim_start_matches('-').to_string(),
+        ))),
+        Value::Float(f) => Ok(SValue::new(Value::Float(f.abs()))),
+        _ => Err(error::Error::InvalidType("number")),
+    }
+}
+
+fn floor(args: Vec<SValue>) -> error::Result<SValue> {
+    to_whole("floor", args, f64::floor)
+}
+
+fn ceil(args: Vec<SValue>) -> error::Result<SValue> {
+    to_whole("ceil", args, f64::ceil)
+}
+
+/// Halves round away from zero
+fn round(args: Vec<SValue>) -> error::Result<SValue> {
+    to_whole("round", args, f64::round)
+}
+
+/// Results too big for `Int` stay floats
+fn to_whole(name: &str, mut args: Vec<SValue>, f: fn(f64) -> f64) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "{name} function expects exactly one argument"
+    );
+    let n = args.remove(0);
+    match &*n {
+        Value::Int(_) | Value::BigInt(_) => Ok(n),
+        Value::Float(x) => {
+            let x = f(*x);
+            // `i64::MAX as f64` is 2^63, one more than `i64::MAX`
+            if x >= i64::MIN as f64 && x < i64::MAX as f64 {
+                Ok(SValue::new(Value::Int(x as i64)))
+            } else {
+                Ok(SValue::new(Value::Float(x)))
+            }
+        }
+        _ => Err(error::Error::InvalidType("number")),
+    }
+}
+
+fn sqrt(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "sqrt function expects exactly one argument"
+    );
+    let n = args.remove(0);
+    let n = n.as_number().ok_or(error::Error::InvalidType("number"))?;
+    if n < 0.0 {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "sqrt function expects a non-negative number, got {n}",
+        )));
+    }
+    Ok(SValue::new(Value::Float(n.sqrt())))
+}
+
+/// An int when all the elements are ints and the total fits, a float otherwise
+fn sum(args: Vec<SValue>) -> error::Result<SValue> {
+    Ok(total(&numbers("sum", args)?))
+}
+
+fn total(numbers: &[SValue]) -> SValue {
+    let ints = numbers
+        .iter()
+        .map(|n| match **n {
+            Value::Int(n) => Some(n),
+            _ => None,
+        })
+        .try_fold(0i64, |total, n| total.checked_add(n?));
+    if let Some(total) = ints {
+        return SValue::new(Value::Int(total));
+    }
+    let total = numbers.iter().filter_map(|n| n.as_number()).sum();
+    SValue::new(Value::Float(total))
+}
+
+fn min(args: Vec<SValue>) -> error::Result<SValue> {
+    extreme("min", args, |n, best| n < best)
+}
+
+fn max(args: Vec<SValue>) -> error::Result<SValue> {
+    extreme("max", args, |n, best| n > best)
+}
+
+/// The first element that no later one beats, kept as it is so ints stay ints
+fn extreme(name: &str, args: Vec<SValue>, beats: fn(f64, f64) -> bool) -> error::Result<SValue> {
+    let numbers = numbers(name, args)?;
+    let mut best = numbers[0].clone();
+    for n in &numbers[1..] {
+        if beats(n.as_number().unwrap(), best.as_number().unwrap()) {
+            best = n.clone();
+        }
+    }
+    Ok(best)
+}
+
+/// The elements of the list argument, which must be a non-empty list of numbers
+fn numbers(name: &str, mut args: Vec<SValue>) -> error::Result<Vec<SValue>> {
+    assert!(
+        args.len() == 1,
+        "{name} function expects exactly one argument"
+    );
+    let list = args.remove(0);
+    let Value::List(list) = &*list else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "{name} function expects a list, got {list}",
+        )));
+    };
+    list.realize_all()?;
+    let elements = list.elements.borrow().clone();
+    if elements.is_empty() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "{name} function expects a non-empty list",
+        )));
+    }
+    if elements.iter().any(|e| e.as_number().is_none()) {
+        return Err(error::Error::InvalidType("number"));
+    }
+    Ok(elements)
+}
+
+/// Apply the key function to each element of the list, returning the keys, which must all be
+/// numbers
+fn aggregate_by(name: &str, mut args: Vec<SValue>) -> error::Result<Vec<SValue>> {
+    let func = args.remove(1);
+    let list = args.remove(0);
+
+    if list.as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "{name} function expects a list as the first argument, got {list}",
+        )));
+    }
+    let Value::Function(func) = &*func else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "{name} function expects a function as the second argument, got {func}",
+        )));
+    };
+
+    let mut keys = vec![];
+    for elem in List::into_iter(list) {
+        let key = (func.implementation)(vec![elem?])?;
+        if key.as_number().is_none() {
+            return Err(error::Error::InvalidType("number"));
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// Number of different keys among the elements of the list. The key is either a field name or a
+/// function of the element.
+fn distinct_count(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "distinct_count function expects exactly two arguments"
+    );
+    let key = args.remove(1);
+    let list = args.remove(0);
+
+    if list.as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "distinct_count function expects a list as the first argument, got {list}",
+        )));
+    }
+    let key_of = |elem: SValue| {
+        match &*key {
+            Value::String(_) => get(vec![elem, key.clone()]),
+            Value::Function(f) => (f.implementation)(vec![elem]),
+            _ => Err(error::Error::BuiltinFunctionError(format!(
+                "distinct_count function expects a field name or a function as the second argument, got {key}",
+            ))),
+        }
+    };
+
+    let mut seen = std::collections::HashSet::new();
+    for elem in List::into_iter(list) {
+        let key = key_of(elem?)?;
+        key.realize()?;
+        seen.insert(canonical_json(&key)?);
+    }
+    Ok(SValue::new(Value::Int(seen.len() as i64)))
+}
+
+/// The elements without repeats, in order of first appearance. An element is a repeat when it's
+/// `==` to one kept before it.
+fn unique(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "unique function expects exactly one argument"
+    );
+    let list = args.remove(0);
+    let Value::List(l) = &*list else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "unique function expects a list, got {list}",
+        )));
+    };
+    l.realize_all()?;
+
+    let mut kept: Vec<SValue> = vec![];
+    for elem in l.elements.borrow().iter() {
+        let mut repeat = false;
+        for k in &kept {
+            if equal(k, elem)? {
+                repeat = true;
+                break;
+            }
+        }
+        if !repeat {
+            kept.push(elem.clone());
+        }
+    }
+    Ok(SValue::new(Value::List(List {
+        elements: kept.into(),
+        rest: None.into(),
+    })))
+}
+
+fn sorted(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "sorted function expects exactly one argument"
+    );
+    let container = args.remove(0);
+
+    match &*container {
+        Value::Dict(dict) => {
+            dict.realize_all()?;
+            let mut elements = dict.elements.borrow().clone();
+            elements.sort_keys();
+            Ok(SValue::new(Value::Dict(crate::data::Dict {
+                elements: elements.into(),
+                rest: None.into(),
+            })))
+        }
+        Value::List(list) => {
+            list.realize_all()?;
+            let mut elements = list.elements.borrow().clone();
+            sort_values(&mut elements)?;
+            Ok(SValue::new(Value::List(List {
+                elements: elements.into(),
+                rest: None.into(),
+            })))
+        }
+        _ => Err(error::Error::BuiltinFunctionError(format!(
+            "sorted function expects a list or a dict, got {container}",
+        ))),
+    }
+}
+
+/// A list is realized entirely first. A string is reversed by characters, so combining marks end
+/// up on the wrong side of their letter.
+fn reverse(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "reverse function expects exactly one argument"
+    );
+    let value = args.remove(0);
+
+    match &*value {
+        Value::List(list) => {
+            list.realize_all()?;
+            let mut elements = list.elements.borrow().clone();
+            elements.reverse();
+            Ok(SValue::new(Value::List(List {
+                elements: elements.into(),
+                rest: None.into(),
+            })))
+        }
+        Value::String(s) => Ok(SValue::new(Value::String(s.chars().rev().collect()))),
+        _ => Err(error::Error::BuiltinFunctionError(format!(
+            "reverse function expects a list or a string, got {value}",
+        ))),
+    }
+}
+
+/// Parse the string with a strftime-like format. Without a time or an offset in the format, the
+/// date is taken to be at midnight or in UTC.
+fn parse_date(mut args: Vec<SValue>) -> error::Result<SValue> {
+    use chrono::{DateTime, NaiveDate, NaiveDateTime};
+
+    assert!(
+        args.len() == 2,
+        "parse_date function expects exactly two arguments"
+    );
+    let format = args.remove(1);
+    let text = args.remove(0);
+    let (Some(text), Some(format)) = (text.as_string(), format.as_string()) else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "parse_date function expects a string and a format string, got {text} and {format}"
+        )));
+    };
+
+    let date = DateTime::parse_from_str(text, format)
+        .or_else(|_| NaiveDateTime::parse_from_str(text, format).map(|d| d.and_utc().into()))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(text, format)
+                .map(|d| d.and_time(chrono::NaiveTime::MIN).and_utc().into())
+        })
+        .map_err(|e| {
+            error::Error::BuiltinFunctionError(format!(
+                "parse_date function can't parse {text:?} as {format:?}: {e}"
+            ))
+        })?;
+    Ok(SValue::new(Value::Date(date)))
+}
+
+/// Write the date with a strftime-like format
+fn format_date(mut args: Vec<SValue>) -> error::Result<SValue> {
+    use chrono::format::{Item, StrftimeItems};
+
+    assert!(
+        args.len() == 2,
+        "format_date function expects exactly two arguments"
+    );
+    let format = args.remove(1);
+    let date = args.remove(0);
+    let (Value::Date(date), Some(format)) = (&*date, format.as_string()) else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "format_date function expects a date and a format string, got {date} and {format}"
+        )));
+    };
+
+    let items: Vec<_> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "format_date function got an invalid format {format:?}"
+        )));
+    }
+    Ok(SValue::new(Value::String(
+        date.format_with_items(items.iter()).to_string(),
+    )))
+}
+
+/// How much later the second date is than the first, in the unit. Negative if it's earlier.
+fn date_diff(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 3,
+        "date_diff function expects exactly three arguments"
+    );
+    let unit = args.remove(2);
+    let to = args.remove(1);
+    let from = args.remove(0);
+    let (Value::Date(from), Value::Date(to)) = (&*from, &*to) else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "date_diff function expects two dates, got {from} and {to}"
+        )));
+    };
+    let unit_ms = match unit.as_string() {
+        Some("milliseconds") => 1.0,
+        Some("seconds") => 1000.0,
+        Some("minutes") => 60.0 * 1000.0,
+        Some("hours") => 60.0 * 60.0 * 1000.0,
+        Some("days") => 24.0 * 60.0 * 60.0 * 1000.0,
+        Some("weeks") => 7.0 * 24.0 * 60.0 * 60.0 * 1000.0,
+        _ => {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "date_diff function expects a unit from milliseconds to weeks, got {unit}"
+            )))
+        }
+    };
+    let diff = to.signed_duration_since(from).num_milliseconds() as f64;
+    Ok(SValue::new(Value::Float(diff / unit_ms)))
+}
+
+/// The current time, in UTC
+fn now(args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(args.is_empty(), "now function expects no arguments");
+    Ok(SValue::new(Value::Date(chrono::Utc::now().fixed_offset())))
+}
+
+/// Seed for the random choices of [`sample_n`], see [`set_seed`]
+static SEED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Builtins whose results depend on more than their arguments, so they can't be memoized
+pub(crate) const UNCACHEABLE: &[&str] = &["sample_n", "now"];
+
+/// Builtins whose last argument, unless it's a lambda or a variable, is evaluated for each
+/// element with `%` bound to it, so `map (% + 1)` works like `map |x| x + 1`
+pub(crate) const PER_ELEMENT: &[&str] = &["map"];
+
+/// Make the random choices of builtins repeat the ones of other runs with the same seed
+pub fn set_seed(seed: u64) {
+    SEED.store(seed, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// `n` different elements of the list, picked at random. Every call starts from the seed, so the
+/// same list gives the same sample until the seed changes.
+fn sample_n(mut args: Vec<SValue>) -> error::Result<SValue> {
+    use rand::seq::IndexedRandom;
+    use rand::SeedableRng;
+
+    assert!(
+        args.len() == 2,
+        "sample_n function expects exactly two arguments"
+    );
+    let n = args.remove(1);
+    let list = args.remove(0);
+
+    let Value::List(l) = &*list else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "sample_n function expects a list as the first argument, got {list}",
+        )));
+    };
+    let n = match *n {
+        Value::Int(n) if n >= 0 => n as usize,
+        _ => {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "sample_n function expects a non-negative integer as the second argument, got {n}",
+            )))
+        }
+    };
+    l.realize_all()?;
+    let elements = l.elements.borrow();
+    if n > elements.len() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "sample_n function can't pick {n} elements out of {}",
+            elements.len()
+        )));
+    }
+    let mut rng =
+        rand::rngs::StdRng::seed_from_u64(SEED.load(std::sync::atomic::Ordering::Relaxed));
+    let picked: Vec<_> = elements.sample(&mut rng, n).cloned().collect();
+    Ok(SValue::new(Value::List(List {
+        elements: picked.into(),
+        rest: None.into(),
+    })))
+}
+
+/// A sorted copy of the list, ordered by its elements or by the keys the function gives them
+fn sort(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1 || args.len() == 2,
+        "sort function expects one or two arguments"
+    );
+    let key = (args.len() == 2).then(|| args.remove(1));
+    let list = args.remove(0);
+
+    let Value::List(l) = &*list else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "sort function expects a list as the first argument, got {list}",
+        )));
+    };
+    l.realize_all()?;
+    let mut elements = l.elements.borrow().clone();
+    match key {
+        None => sort_values(&mut elements)?,
+        Some(key) => {
+            let Value::Function(f) = &*key else {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "sort function expects a function as the second argument, got {key}",
+                )));
+            };
+            let mut keyed = elements
+                .into_iter()
+                .map(|e| Ok(((f.implementation)(vec![e.clone()])?, e)))
+                .collect::<error::Result<Vec<_>>>()?;
+            sort_values_by(&mut keyed, |(k, _)| k)?;
+            elements = keyed.into_iter().map(|(_, e)| e).collect();
+        }
+    }
+    Ok(SValue::new(Value::List(List {
+        elements: elements.into(),
+        rest: None.into(),
+    })))
+}
+
+/// Natural ordering: numbers by value, strings lexicographically, dates chronologically
+pub(crate) fn compare(a: &Value, b: &Value) -> error::Result<std::cmp::Ordering> {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        (Value::Date(a), Value::Date(b)) => Ok(a.cmp(b)),
+        _ => match (a.as_number(), b.as_number()) {
+            (Some(a), Some(b)) => Ok(a.total_cmp(&b)),
+            _ => Err(error::Error::InvalidType("number, string or date")),
+        },
+    }
+}
+
+/// Equality as `==` sees it: numbers by value whether they're integers or floats, containers
+/// realized and compared element by element, dicts regardless of key order
+pub(crate) fn equal(a: &Value, b: &Value) -> error::Result<bool> {
+    if let (Some(a), Some(b)) = (a.as_number(), b.as_number()) {
+        return Ok(a == b);
+    }
+    a.realize()?;
+    b.realize()?;
+    Ok(equal_realized(a, b, false))
+}
+
+/// Stable sort by [`compare`], failing if any two elements are unorderable
+fn sort_values(values: &mut [SValue]) -> error::Result<()> {
+    sort_values_by(values, |v| v)
+}
+
+/// Stable sort by [`compare`] of the keys, failing if any two keys are unorderable
+fn sort_values_by<T>(values: &mut [T], key: impl Fn(&T) -> &Value) -> error::Result<()> {
+    let mut err = None;
+    values.sort_by(|a, b| {
+        compare(key(a), key(b)).unwrap_or_else(|e| {
+            err.get_or_insert(e);
+            std::cmp::Ordering::Equal
+        })
+    });
+    match err {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn only(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "only function expects exactly one argument"
+    );
+    let container = args.remove(0);
+
+    // Realizing two elements is enough to tell "one" from "more than one"
+    let mut values = match &*container {
+        Value::List(list) => {
+            list.get(1)?;
+            list.elements
+                .borrow()
+                .iter()
+                .take(2)
+                .cloned()
+                .collect::<Vec<_>>()
+        }
+        Value::Dict(dict) => {
+            dict.realize_n(2)?;
+            dict.elements.borrow().values().take(2).cloned().collect()
+        }
+        _ => {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "only function expects a list or a dict, got {container}",
+            )))
+        }
+    };
+    if values.len() != 1 {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "only function expects exactly one element, got {container}",
+        )));
+    }
+    Ok(values.remove(0))
+}
+
+/// Lazily apply the function to each element of a list, or each value of a dict keeping the keys.
+/// The function can also be an expression using `%`, see [`PER_ELEMENT`].
+fn map(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "map function expects exactly two arguments"
+    );
+    let func = args.remove(1);
+    let container = args.remove(0);
+
+    if !matches!(&*func, Value::Function(_)) {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "map function expects a function as the second argument, got {func}",
+        )));
+    }
+    let call = move |v: SValue| {
+        let Value::Function(f) = &*func else {
+            unreachable!()
+        };
+        (f.implementation)(vec![v])
+    };
+
+    match &*container {
+        Value::List(_) => Ok(SValue::new(Value::List(List {
+            elements: vec![].into(),
+            rest: Some(
+                Box::new(List::into_iter(container).map(move |e| e.and_then(&call)))
+                    as BoxedRest<_>,
+            )
+            .into(),
+        }))),
+        Value::Dict(_) => Ok(SValue::new(Value::Dict(crate::data::Dict {
+            elements: IndexMap::new().into(),
+            rest: Some(Box::new(
+                crate::data::Dict::into_iter(container)
+                    .map(move |e| e.and_then(|(k, v)| Ok((k, call(v)?)))),
+            ) as BoxedRest<_>)
+            .into(),
+        }))),
+        _ => Err(error::Error::BuiltinFunctionError(format!(
+            "map function expects a list or a dict as the first argument, got {container}",
+        ))),
+    }
+}
+
+/// Like [`map`] over a list, but realizes it and applies the function on a thread pool. The
+/// threads keep to the timeout of the caller.
+#[cfg(feature = "threadsafe")]
+fn pmap(mut args: Vec<SValue>) -> error::Result<SValue> {
+    use rayon::prelude::*;
+
+    assert!(
+        args.len() == 2,
+        "pmap function expects exactly two arguments"
+    );
+    let func = args.remove(1);
+    let container = args.remove(0);
+
+    let Value::Function(f) = &*func else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "pmap function expects a function as the second argument, got {func}",
+        )));
+    };
+    let Value::List(list) = &*container else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "pmap function expects a list as the first argument, got {container}",
+        )));
+    };
+    list.realize_all()?;
+    let elements = list.elements.borrow().clone();
+    let deadline = crate::data::deadline();
+    let mapped = elements
+        .into_par_iter()
+        .map(|e| {
+            let remaining =
+                deadline.map(|d| d.saturating_duration_since(std::time::Instant::now()));
+            crate::data::with_deadline(remaining, || (f.implementation)(vec![e]))
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+
+    Ok(SValue::new(Value::List(List {
+        elements: mapped.into(),
+        rest: None.into(),
+    })))
+}
+
+/// Lazily keep the entries of a dict for which the predicate, called with the key and the value,
+/// returns true
+fn filter_dict(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "filter_dict function expects exactly two arguments"
+    );
+    let predicate = args.remove(1);
+    let container = args.remove(0);
+
+    if !matches!(&*predicate, Value::Function(_)) {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "filter_dict function expects a function as the second argument, got {predicate}",
+        )));
+    }
+    if container.as_dict().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "filter_dict function expects a dict as the first argument, got {container}",
+        )));
+    }
+
+    let entries = crate::data::Dict::into_iter(container).filter_map(move |e| {
+        let Value::Function(f) = &*predicate else {
+            unreachable!()
+        };
+        let keep = |(k, v): (String, SValue)| {
+            let result =
+                (f.implementation)(vec![SValue::new(Value::String(k.clone())), v.clone()])?;
+            match result.as_bool() {
+                Some(true) => Ok(Some((k, v))),
+                Some(false) => Ok(None),
+                None => Err(error::Error::BuiltinFunctionError(format!(
+                    "filter_dict function expects the predicate to return a boolean, got {result}"
+                ))),
+            }
+        };
+        e.and_then(keep).transpose()
+    });
+    Ok(SValue::new(Value::Dict(crate::data::Dict {
+        elements: IndexMap::new().into(),
+        rest: Some(Box::new(entries) as BoxedRest<_>).into(),
+    })))
+}
+
+/// Fill the `{key}` placeholders of the template with the values in the dict. Strings are inserted
+/// as they are, other values as they are displayed. `{{` and `}}` are literal braces, and a key
+/// missing from the dict is an error.
+fn render(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "render function expects exactly two arguments"
+    );
+    let fields = args.remove(1);
+    let template = args.remove(0);
+
+    let Value::String(template) = &*template else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "render function expects a string as the first argument, got {template}"
+        )));
+    };
+    let Value::Dict(fields) = &*fields else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "render function expects a dict as the second argument, got {fields}"
+        )));
+    };
+
+    let mut result = String::new();
+    let mut rest = template.as_str();
+    while let Some(i) = rest.find(['{', '}']) {
+        result.push_str(&rest[..i]);
+        let (brace, after) = rest[i..].split_at(1);
+        if let Some(after) = after.strip_prefix(brace) {
+            result.push_str(brace);
+            rest = after;
+            continue;
+        }
+        let Some((key, after)) = after.split_once('}').filter(|_| brace == "{") else {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "render function got an unmatched {brace} in the template"
+            )));
+        };
+        rest = after;
+        let value = fields.get(key)?.ok_or_else(|| {
+            error::Error::BuiltinFunctionError(format!(
+                "render function got a placeholder for a missing key: {key}"
+            ))
+        })?;
+        value.realize()?;
+        match &*value {
+            Value::String(s) => result.push_str(s),
+            value => result.push_str(&value.to_string()),
+        }
+    }
+    result.push_str(rest);
+    Ok(SValue::new(Value::String(result)))
+}
+
+/// The elements of the list for which the predicate returns true, lazily
+fn matching(
+    name: &str,
+    mut args: Vec<SValue>,
+) -> error::Result<impl Iterator<Item = error::Result<SValue>>> {
+    assert!(
+        args.len() == 2,
+        "{name} function expects exactly two arguments"
+    );
+    let predicate = args.remove(1);
+    let container = args.remove(0);
+
+    if !matches!(&*predicate, Value::Function(_)) {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "{name} function expects a function as the second argument, got {predicate}",
+        )));
+    }
+    if container.as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "{name} function expects a list as the first argument, got {container}",
+        )));
+    }
+
+    Ok(List::into_iter(container).filter_map(move |e| {
+        let Value::Function(f) = &*predicate else {
+            unreachable!()
+        };
+        let keep = |e: SValue| {
+            let result = (f.implementation)(vec![e.clone()])?;
+            match result.as_bool() {
+                Some(true) => Ok(Some(e)),
+                Some(false) => Ok(None),
+                None => Err(error::Error::InvalidType("boolean")),
+            }
+        };
+        e.and_then(keep).transpose()
+    }))
+}
+
+/// Fold the elements of a list, or the values of a dict, into the accumulator with a function of
+/// `(acc, element)`. This realizes the whole container, so a list known to be endless is an error.
+/// Only lists that report being endless themselves, like `cycle`, are caught up front: wrapped in
+/// something like `filter`, the length is unknown, and folding runs until the `.timeout`.
+fn reduce(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 3,
+        "reduce function expects exactly three arguments"
+    );
+    let func = args.remove(2);
+    let mut acc = args.remove(1);
+    let container = args.remove(0);
+
+    let Value::Function(f) = &*func else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "reduce function expects a function as the third argument, got {func}",
+        )));
+    };
+    let elements: BoxedRest<SValue> = match &*container {
+        Value::List(_) => {
+            let elements = List::into_iter(container);
+            // Infinite iterators report a lower bound of `usize::MAX`
+            if elements.size_hint().0 == usize::MAX {
+                return Err(error::Error::BuiltinFunctionError(
+                    "reduce function can't fold an endless list".to_string(),
+                ));
+            }
+            Box::new(elements)
+        }
+        Value::Dict(_) => {
+            Box::new(crate::data::Dict::into_iter(container).map(|e| e.map(|(_, v)| v)))
+        }
+        _ => {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "reduce function expects a list or a dict as the first argument, got {container}",
+            )))
+        }
+    };
+    for elem in elements {
+        acc = (f.implementation)(vec![acc, elem?])?;
+    }
+    Ok(acc)
+}
+
+/// Number of elements of a list or dict, or characters of a string. This forces the whole list
+/// or dict to be realized.
+fn len(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(args.len() == 1, "len function expects exactly one argument");
+    let value = args.remove(0);
+    let len = match &*value {
+        Value::List(l) => {
+            l.realize_all()?;
+            l.elements.borrow().len()
+        }
+        Value::Dict(d) => {
+            d.realize_all()?;
+            d.elements.borrow().len()
+        }
+        Value::String(s) => s.chars().count(),
+        _ => return Err(error::Error::InvalidTypes(&["list", "dict", "string"])),
+    };
+    Ok(SValue::new(Value::Int(len as i64)))
+}
+
+/// Lazy list of the keys of a dict in insertion order, or the indices of a list
+fn keys(args: Vec<SValue>) -> error::Result<SValue> {
+    entries_list("keys", args, |k, _| k)
+}
+
+/// Lazy list of the values of a dict in insertion order, or the elements of a list
+fn values(args: Vec<SValue>) -> error::Result<SValue> {
+    entries_list("values", args, |_, v| v)
+}
+
+/// Lazy list of `[key, value]` pairs of a dict, or `[index, element]` pairs of a list
+fn entries(args: Vec<SValue>) -> error::Result<SValue> {
+    entries_list("entries", args, pair)
+}
+
+/// Lazy list of `[index, element]` pairs, like `entries` but only for lists
+fn enumerate(args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "enumerate function expects exactly one argument"
+    );
+    if args[0].as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "enumerate function expects a list, got {}",
+            args[0]
+        )));
+    }
+    entries_list("enumerate", args, pair)
+}
+
+/// Lazy list of `[a, b]` pairs of the elements at the same index, as long as the shorter list
+fn zip(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "zip function expects exactly two arguments"
+    );
+    let second = args.remove(1);
+    let first = args.remove(0);
+    for list in [&first, &second] {
+        if list.as_list().is_none() {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "zip function expects two lists, got {list}"
+            )));
+        }
+    }
+    let pairs = List::into_iter(first)
+        .zip(List::into_iter(second))
+        .map(|(a, b)| Ok(pair(a?, b?)));
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new(pairs) as BoxedRest<_>).into(),
+    })))
+}
+
+fn pair(first: SValue, second: SValue) -> SValue {
+    SValue::new(Value::List(List {
+        elements: vec![first, second].into(),
+        rest: None.into(),
+    }))
+}
+
+/// Lazy list with an element made from each key and value of the dict, or index and element of
+/// the list
+fn entries_list(
+    name: &str,
+    mut args: Vec<SValue>,
+    element: fn(SValue, SValue) -> SValue,
+) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "{name} function expects exactly one argument"
+    );
+    let container = args.remove(0);
+    let entries: BoxedRest<SValue> = match &*container {
+        Value::Dict(_) => Box::new(
+            crate::data::Dict::into_iter(container)
+                .map(move |e| e.map(|(k, v)| element(SValue::new(Value::String(k)), v))),
+        ),
+        Value::List(_) => Box::new(
+            List::into_iter(container)
+                .enumerate()
+                .map(move |(i, e)| e.map(|v| element(SValue::new(Value::Int(i as i64)), v))),
+        ),
+        _ => {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "{name} function expects a list or a dict, got {container}",
+            )))
+        }
+    };
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(entries).into(),
+    })))
+}
+
+/// Lazily keep the elements of the list that the predicate is true for
+fn filter(args: Vec<SValue>) -> error::Result<SValue> {
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new(matching("filter", args)?) as BoxedRest<_>).into(),
+    })))
+}
+
+/// Only realizes the first element, so it works on infinite lists
+fn first(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "first function expects exactly one argument"
+    );
+    let list = args.remove(0);
+    let list = list.as_list().ok_or(error::Error::InvalidType("list"))?;
+    list.get(0)?.ok_or_else(|| {
+        error::Error::BuiltinFunctionError("first function expects a non-empty list".to_string())
+    })
+}
+
+/// Has to realize the whole list, unlike `first`
+fn last(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "last function expects exactly one argument"
+    );
+    let list = args.remove(0);
+    let list = list.as_list().ok_or(error::Error::InvalidType("list"))?;
+    list.realize_all()?;
+    let last = list.elements.borrow().last().cloned();
+    last.ok_or_else(|| {
+        error::Error::BuiltinFunctionError("last function expects a non-empty list".to_string())
+    })
+}
+
+/// Stops at the first match, so it works on infinite lists. Null if nothing matches.
+fn first_where(args: Vec<SValue>) -> error::Result<SValue> {
+    matching("first_where", args)?
+        .next()
+        .unwrap_or_else(|| Ok(SValue::new(Value::Null)))
+}
+
+/// Null if nothing matches
+fn last_where(args: Vec<SValue>) -> error::Result<SValue> {
+    let mut last = SValue::new(Value::Null);
+    for e in matching("last_where", args)? {
+        last = e?;
+    }
+    Ok(last)
+}
+
+/// Only realizes the first entry of the dict
+fn first_key(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "first_key function expects exactly one argument"
+    );
+    let container = args.remove(0);
+    let Value::Dict(dict) = &*container else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "first_key function expects a dict, got {container}",
+        )));
+    };
+    Ok(SValue::new(
+        dict.get_first()?
+            .map(|(k, _)| Value::String(k))
+            .unwrap_or(Value::Null),
+    ))
+}
+
+/// Realizes the whole dict
+fn last_key(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "last_key function expects exactly one argument"
+    );
+    let container = args.remove(0);
+    let Value::Dict(dict) = &*container else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "last_key function expects a dict, got {container}",
+        )));
+    };
+    dict.realize_all()?;
+    let key = dict
+        .elements
+        .borrow()
+        .last()
+        .map(|(k, _)| Value::String(k.clone()));
+    Ok(SValue::new(key.unwrap_or(Value::Null)))
+}
+
+/// Group the elements by the string key the function gives them, keys in order of first
+/// appearance. Unlike [`lazy_group_by`] the list doesn't need to be sorted, but it is realized
+/// entirely to find every element of the first group.
+fn group_by(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "group_by function expects exactly two arguments"
+    );
+    let func = args.remove(1);
+    let list = args.remove(0);
+
+    if list.as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "group_by function expects a list as the first argument, got {list}",
+        )));
+    }
+    let Value::Function(f) = &*func else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "group_by function expects a function as the second argument, got {func}",
+        )));
+    };
+
+    let mut groups: IndexMap<String, Vec<SValue>> = IndexMap::new();
+    for elem in List::into_iter(list) {
+        let elem = elem?;
+        let key = (f.implementation)(vec![elem.clone()])?;
+        let key = key.as_string().ok_or(error::Error::InvalidType("string"))?;
+        groups.entry(key.to_string()).or_default().push(elem);
+    }
+    let groups = groups
+        .into_iter()
+        .map(|(k, group)| {
+            let group = Value::List(List {
+                elements: group.into(),
+                rest: None.into(),
+            });
+            (k, SValue::new(group))
+        })
+        .collect::<IndexMap<_, _>>();
+    Ok(SValue::new(Value::Dict(crate::data::Dict {
+        elements: groups.into(),
+        rest: None.into(),
+    })))
+}
+
+/// Group consecutive elements with the same key, producing each group only when it's needed.
+///
+/// The list has to be sorted by the key already: a key that appears again after a different one
+/// makes its later group replace the earlier one.
+fn lazy_group_by(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "lazy_group_by function expects exactly two arguments"
+    );
+    let func = args.remove(1);
+    let list = args.remove(0);
+
+    if list.as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "lazy_group_by function expects a list as the first argument, got {list}",
+        )));
+    }
+    if !matches!(&*func, Value::Function(_)) {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "lazy_group_by function expects a function as the second argument, got {func}",
+        )));
+    }
+
+    let mut source = List::into_iter(list);
+    let mut next_keyed = move || -> error::Result<Option<(String, SValue)>> {
+        let Some(elem) = source.next().transpose()? else {
+            return Ok(None);
+        };
+        let Value::Function(f) = &*func else {
+            unreachable!()
+        };
+        let key = (f.implementation)(vec![elem.clone()])?;
+        let key = key
+            .as_string()
+            .ok_or(error::Error::InvalidType("string"))?
+            .to_string();
+        Ok(Some((key, elem)))
+    };
+    // The element that ended the previous group
+    let mut pending = None;
+    let mut next_group = move || -> error::Result<Option<(String, SValue)>> {
+        let (key, first) = match pending.take() {
+            Some(pending) => pending,
+            None => match next_keyed()? {
+                Some(next) => next,
+                None => return Ok(None),
+            },
+        };
+        let mut group = vec![first];
+        while let Some((k, elem)) = next_keyed()? {
+            if k != key {
+                pending = Some((k, elem));
+                break;
+            }
+            group.push(elem);
+        }
+        Ok(Some((
+            key,
+            SValue::new(Value::List(List {
+                elements: group.into(),
+                rest: None.into(),
+            })),
+        )))
+    };
+
+    Ok(SValue::new(Value::Dict(crate::data::Dict {
+        elements: IndexMap::new().into(),
+        rest: Some(Box::new(std::iter::from_fn(move || next_group().transpose())) as BoxedRest<_>)
+            .into(),
+    })))
+}
+
+/// Turn nested dicts and lists into a single dict keyed by paths like `a.b[0]`.
+/// Empty containers are kept as values.
+fn flatten_dict(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "flatten_dict function expects exactly one argument"
+    );
+    let container = args.remove(0);
+    if container.as_dict().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "flatten_dict function expects a dict, got {container}",
+        )));
+    }
+
+    fn flatten_into(
+        prefix: &str,
+        value: SValue,
+        result: &mut IndexMap<String, SValue>,
+    ) -> error::Result<()> {
+        value.realize()?;
+        match &*value {
+            Value::Dict(d) if !d.elements.borrow().is_empty() => {
+                for (k, v) in d.elements.borrow().iter() {
+                    let path = if prefix.is_empty() {
+                        k.clone()
+                    } else {
+                        format!("{prefix}.{k}")
+                    };
+                    flatten_into(&path, v.clone(), result)?;
+                }
+            }
+            Value::List(l) if !l.elements.borrow().is_empty() => {
+                for (i, v) in l.elements.borrow().iter().enumerate() {
+                    flatten_into(&format!("{prefix}[{i}]"), v.clone(), result)?;
+                }
+            }
+            _ => {
+                result.insert(prefix.to_string(), value.clone());
+            }
+        }
+        Ok(())
+    }
+
+    let mut result = IndexMap::new();
+    flatten_into("", container, &mut result)?;
+    Ok(SValue::new(Value::Dict(crate::data::Dict {
+        elements: result.into(),
+        rest: None.into(),
+    })))
+}
+
+/// The inverse of [`flatten_dict`]. Keys that contain `.` or `[` themselves can't be told apart
+/// from paths.
+fn unflatten_dict(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "unflatten_dict function expects exactly one argument"
+    );
+    let container = args.remove(0);
+    let Value::Dict(dict) = &*container else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "unflatten_dict function expects a dict, got {container}",
+        )));
+    };
+
+    enum Segment {
+        Key(String),
+        Index(usize),
+    }
+
+    enum Tree {
+        Empty,
+        Leaf(SValue),
+        Dict(IndexMap<String, Tree>),
+        List(Vec<Tree>),
+    }
+
+    fn parse_path(path: &str) -> error::Result<Vec<Segment>> {
+        let invalid =
+            || error::Error::BuiltinFunctionError(format!("invalid flattened key: {path}"));
+        let mut segments = vec![];
+        for part in path.split('.') {
+            let (key, mut indices) = part.split_once('[').map_or((part, ""), |(k, i)| (k, i));
+            segments.push(Segment::Key(key.to_string()));
+            while !indices.is_empty() {
+                let (index, rest) = indices.split_once(']').ok_or_else(invalid)?;
+                segments.push(Segment::Index(index.parse().map_err(|_| invalid())?));
+                indices = match rest {
+                    "" => "",
+                    rest => rest.strip_prefix('[').ok_or_else(invalid)?,
+                };
+            }
+        }
+        Ok(segments)
+    }
+
+    fn insert(tree: &mut Tree, path: &[Segment], value: SValue) -> error::Result<()> {
+        let Some((segment, rest)) = path.split_first() else {
+            *tree = Tree::Leaf(value);
+            return Ok(());
+        };
+        match (segment, &mut *tree) {
+            (Segment::Key(_), Tree::Empty) => *tree = Tree::Dict(IndexMap::new()),
+            (Segment::Index(_), Tree::Empty) => *tree = Tree::List(vec![]),
+            _ => (),
+        }
+        match (segment, tree) {
+            (Segment::Key(k), Tree::Dict(d)) => {
+                insert(d.entry(k.clone()).or_insert(Tree::Empty), rest, value)
+            }
+            (Segment::Index(i), Tree::List(l)) => {
+                while l.len() <= *i {
+                    l.push(Tree::Empty);
+                }
+                insert(&mut l[*i], rest, value)
+            }
+            _ => Err(error::Error::BuiltinFunctionError(
+                "flattened keys use the same path for different kinds of values".to_string(),
+            )),
+        }
+    }
+
+    fn build(tree: Tree) -> SValue {
+        match tree {
+            Tree::Empty => SValue::new(Value::Null),
+            Tree::Leaf(v) => v,
+            Tree::Dict(d) => SValue::new(Value::Dict(crate::data::Dict {
+                elements: d
+                    .into_iter()
+                    .map(|(k, v)| (k, build(v)))
+                    .collect::<IndexMap<_, _>>()
+                    .into(),
+                rest: None.into(),
+            })),
+            Tree::List(l) => SValue::new(Value::List(List {
+                elements: l.into_iter().map(build).collect::<Vec<_>>().into(),
+                rest: None.into(),
+            })),
+        }
+    }
+
+    dict.realize_all()?;
+    let mut tree = Tree::Dict(IndexMap::new());
+    for (k, v) in dict.elements.borrow().iter() {
+        insert(&mut tree, &parse_path(k)?, v.clone())?;
+    }
+    Ok(build(tree))
+}
+
+/// Pair up a list of keys with a list of values, stopping at the shorter one
+fn zip_to_dict(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "zip_to_dict function expects exactly two arguments"
+    );
+    let values = args.remove(1);
+    let keys = args.remove(0);
+    for list in [&keys, &values] {
+        if list.as_list().is_none() {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "zip_to_dict function expects two lists, got {list}"
+            )));
+        }
+    }
+
+    let mut result = IndexMap::new();
+    for (k, v) in List::into_iter(keys).zip(List::into_iter(values)) {
+        let k = k?;
+        let Value::String(k) = &*k else {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "zip_to_dict function expects string keys, got {k}"
+            )));
+        };
+        result.insert(k.clone(), v?);
+    }
+    Ok(SValue::new(Value::Dict(crate::data::Dict {
+        elements: result.into(),
+        rest: None.into(),
+    })))
+}
+
+/// Render a list of dicts as a Markdown table, with a column for every key in first-seen order
+fn to_markdown_table(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "to_markdown_table function expects exactly one argument"
+    );
+    let rows = args.remove(0);
+    let Value::List(list) = &*rows else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "to_markdown_table function expects a list of dicts, got {rows}"
+        )));
+    };
+    rows.realize()?;
+
+    let mut columns: Vec<String> = vec![];
+    for row in list.elements.borrow().iter() {
+        let Value::Dict(d) = &**row else {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "to_markdown_table function expects a list of dicts, got {row}"
+            )));
+        };
+        for k in d.elements.borrow().keys() {
+            if !columns.contains(k) {
+                columns.push(k.clone());
+            }
+        }
+    }
+
+    fn line(cells: impl Iterator<Item = String>) -> String {
+        let cells: Vec<_> = cells
+            .map(|cell| cell.replace('|', "\\|").replace('\n', " "))
+            .collect();
+        format!("| {} |", cells.join(" | "))
+    }
+
+    let mut lines = vec![
+        line(columns.iter().cloned()),
+        line(columns.iter().map(|_| "---".to_string())),
+    ];
+    for row in list.elements.borrow().iter() {
+        let Value::Dict(d) = &**row else {
+            unreachable!()
+        };
+        let elements = d.elements.borrow();
+        lines.push(line(columns.iter().map(
+            |c| match elements.get(c).map(|v| &**v) {
+                None => String::new(),
+                Some(Value::String(s)) => s.clone(),
+                Some(v) => v.to_string(),
+            },
+        )));
+    }
+    Ok(SValue::new(Value::String(lines.join("\n"))))
+}
+
+/// Lazily drop the elements of a list that fail to realize, reporting them on stderr
+fn skip_errors(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "skip_errors function expects exactly one argument"
+    );
+    let source = args.remove(0);
+    if source.as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "skip_errors function expects a list, got {source}"
+        )));
+    }
+
+    // Can't go through `List::get`, an error would shift the indices of the elements after it
+    let mut index = 0;
+    let rest = std::iter::from_fn(move || {
+        let list = source.as_list().unwrap();
+        loop {
+            if let Some(elem) = list.elements.borrow().get(index) {
+                index += 1;
+                return Some(Ok(elem.clone()));
+            }
+            let next = list.rest.borrow_mut().as_mut()?.next();
+            match next {
+                Some(Ok(elem)) => list.elements.borrow_mut().push(elem),
+                Some(Err(err)) => eprintln!("Skipping element: {err}"),
+                None => {
+                    *list.rest.borrow_mut() = None;
+                    return None;
+                }
+            }
+        }
+    });
+
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new(rest) as BoxedRest<_>).into(),
+    })))
+}
+
+/// See [`type_of`]. Dates and integers too big for `Int` are `"date"` and `"int"`.
+fn type_name(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "type function expects exactly one argument"
+    );
+    let value = args.remove(0);
+    Ok(SValue::new(Value::String(type_of(&value).to_string())))
+}
+
+/// Name of the kind of value
+pub(crate) fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Int(_) | Value::BigInt(_) => "int",
+        Value::Float(_) => "float",
+        Value::Date(_) => "date",
+        Value::String(_) => "string",
+        Value::List(_) => "list",
+        Value::Dict(_) => "dict",
+        Value::Function(_) => "function",
+    }
+}
+
+/// The shape of a realized value, like `{id: int, tags: [string]}`. A list shows the different
+/// shapes of its elements joined with `|`. With `collapse_numbers`, ints and floats are both
+/// `number`.
+pub(crate) fn schema(value: &Value, collapse_numbers: bool) -> String {
+    match value {
+        Value::List(l) => {
+            let mut shapes: Vec<String> = vec![];
+            for e in l.elements.borrow().iter() {
+                let shape = schema(e, collapse_numbers);
+                if !shapes.contains(&shape) {
+                    shapes.push(shape);
+                }
+            }
+            format!("[{}]", shapes.join("|"))
+        }
+        Value::Dict(d) => {
+            let fields: Vec<String> = d
+                .elements
+                .borrow()
+                .iter()
+                .map(|(k, v)| format!("{k}: {}", schema(v, collapse_numbers)))
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+        Value::Int(_) | Value::BigInt(_) | Value::Float(_) if collapse_numbers => {
+            "number".to_string()
+        }
+        v => type_of(v).to_string(),
+    }
+}
+
+/// Summarize each field of a list of records: how many are non-null, and min/max/mean for numeric
+/// fields or the number of distinct values for string fields
+fn describe(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "describe function expects exactly one argument"
+    );
+    let records = args.remove(0);
+    if records.as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "describe function expects a list of dicts, got {records}"
+        )));
+    }
+
+    #[derive(Default)]
+    struct Summary {
+        count: usize,
+        numbers: usize,
+        min: f64,
+        max: f64,
+        sum: f64,
+        strings: std::collections::HashSet<String>,
+        string_count: usize,
+    }
+
+    let mut fields: IndexMap<String, Summary> = IndexMap::new();
+    for record in List::into_iter(records) {
+        let record = record?;
+        let Value::Dict(d) = &*record else {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "describe function expects a list of dicts, got {record}"
+            )));
+        };
+        d.realize_all()?;
+        for (k, v) in d.elements.borrow().iter() {
+            let summary = fields.entry(k.clone()).or_default();
+            if let Value::Null = **v {
+                continue;
+            }
+            summary.count += 1;
+            if let Some(n) = v.as_number() {
+                if summary.numbers == 0 {
+                    (summary.min, summary.max) = (n, n);
+                }
+                summary.numbers += 1;
+                summary.min = summary.min.min(n);
+                summary.max = summary.max.max(n);
+                summary.sum += n;
+            } else if let Value::String(s) = &**v {
+                summary.string_count += 1;
+                summary.strings.insert(s.clone());
+            }
+        }
+    }
+
+    let fields = fields.into_iter().map(|(k, summary)| {
+        let mut stats = IndexMap::new();
+        stats.insert(
+            "count".to_string(),
+            SValue::new(Value::Int(summary.count as _)),
+        );
+        if summary.count > 0 && summary.numbers == summary.count {
+            stats.insert("min".to_string(), SValue::new(Value::Float(summary.min)));
+            stats.insert("max".to_string(), SValue::new(Value::Float(summary.max)));
+            stats.insert(
+                "mean".to_string(),
+                SValue::new(Value::Float(summary.sum / summary.numbers as f64)),
+            );
+        } else if summary.count > 0 && summary.string_count == summary.count {
+            stats.insert(
+                "distinct".to_string(),
+                SValue::new(Value::Int(summary.strings.len() as _)),
+            );
+        }
+        let stats = SValue::new(Value::Dict(crate::data::Dict {
+            elements: stats.into(),
+            rest: None.into(),
+        }));
+        (k, stats)
+    });
+    Ok(SValue::new(Value::Dict(crate::data::Dict {
+        elements: fields.collect::<IndexMap<_, _>>().into(),
+        rest: None.into(),
+    })))
+}
+
+/// Lazy list of the integers from the start (0 if not given) up to but not including the end
+fn range(args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1 || args.len() == 2,
+        "range function expects one or two arguments"
+    );
+    let bounds = args
+        .iter()
+        .map(|bound| match **bound {
+            Value::Int(n) => Ok(n),
+            _ => Err(error::Error::BuiltinFunctionError(format!(
+                "range function expects integer bounds, got {bound}"
+            ))),
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+    let (start, end) = match bounds[..] {
+        [end] => (0, end),
+        [start, end] => (start, end),
+        _ => unreachable!(),
+    };
+
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new((start..end).map(|n| Ok(SValue::new(Value::Int(n))))) as BoxedRest<_>)
+            .into(),
+    })))
+}
+
+/// Concatenate the lists inside the list, one level deep. Each inner list is only realized as
+/// its elements are reached.
+fn flatten(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "flatten function expects exactly one argument"
     );
+    let source = args.remove(0);
+    if source.as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "flatten function expects a list, got {source}"
+        )));
+    }
+    let elements = List::into_iter(source).flat_map(|inner| -> BoxedRest<SValue> {
+        match inner {
+            Ok(inner) if inner.as_list().is_some() => Box::new(List::into_iter(inner)),
+            Ok(_) => Box::new(std::iter::once(Err(error::Error::InvalidType("list")))),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    });
 
-    functions
-        .into_iter()
-        .map(|(k, v)| (k, SValue::new(Value::Function(v))))
-        .collect()
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new(elements) as BoxedRest<_>).into(),
+    })))
 }
 
-fn get(mut args: Vec<SValue>) -> error::Result<SValue> {
+/// Functions can't take any number of arguments, so `concat` takes up to this many lists
+const MAX_CONCAT: usize = 8;
+
+/// The elements of each list in order. A list is only realized once the ones before it run out.
+fn concat(args: Vec<SValue>) -> error::Result<SValue> {
     assert!(
-        args.len() == 2,
-        "get function expects exactly two arguments"
+        (1..=MAX_CONCAT).contains(&args.len()),
+        "concat function expects one to {MAX_CONCAT} arguments"
     );
-    let key = args.remove(1);
-    let container = args.remove(0);
+    if let Some(arg) = args.iter().find(|arg| arg.as_list().is_none()) {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "concat function expects lists, got {arg}"
+        )));
+    }
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new(args.into_iter().flat_map(List::into_iter)) as BoxedRest<_>).into(),
+    })))
+}
 
-    match &*key {
-        Value::String(s) => {
-            let Value::Dict(dict) = &*container else {
-                return Err(error::Error::BuiltinFunctionError(format!(
-                    "get function expects a dict as the first argument, got {:?}",
-                    container
-                )));
-            };
-            let value = dict
-                .elements
-                .borrow()
-                .get(s)
-                .cloned()
-                .unwrap_or_else(|| SValue::new(Value::Null));
-            Ok(value)
+/// Repeat the elements of a finite list forever
+fn cycle(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "cycle function expects exactly one argument"
+    );
+    let source = args.remove(0);
+    let Value::List(list) = &*source else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "cycle function expects a list, got {source}"
+        )));
+    };
+    list.realize_all()?;
+    let elements = list.elements.borrow().clone();
+
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new(elements.into_iter().cycle().map(Ok)) as BoxedRest<_>).into(),
+    })))
+}
+
+/// The first n elements of a list, without realizing the rest
+fn take(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "take function expects exactly two arguments"
+    );
+    let n = args.remove(1);
+    let source = args.remove(0);
+    if source.as_list().is_none() {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "take function expects a list as the first argument, got {source}"
+        )));
+    }
+    let n = match *n {
+        Value::Int(n) if n >= 0 => n as usize,
+        _ => {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "take function expects a non-negative integer as the second argument, got {n}"
+            )))
         }
-        Value::Int(n) => {
-            let Value::List(list) = &*container else {
-                return Err(error::Error::BuiltinFunctionError(format!(
-                    "get function expects a list as the first argument, got {:?}",
-                    container
-                )));
-            };
-            list.get(*n as usize)?.ok_or_else(|| {
-                error::Error::BuiltinFunctionError(format!("index out of bounds: {}", n))
-            })
+    };
+
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new(List::into_iter(source).take(n)) as BoxedRest<_>).into(),
+    })))
+}
+
+fn string_arg(name: &str, mut args: Vec<SValue>) -> error::Result<String> {
+    assert!(
+        args.len() == 1,
+        "{name} function expects exactly one argument"
+    );
+    let arg = args.remove(0);
+    match &*arg {
+        Value::String(s) => Ok(s.clone()),
+        _ => Err(error::Error::BuiltinFunctionError(format!(
+            "{name} function expects a string, got {arg}"
+        ))),
+    }
+}
+
+/// Percent-encode everything except the unreserved characters of RFC 3986
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> error::Result<String> {
+    let invalid = || error::Error::BuiltinFunctionError(format!("invalid percent-encoding: {s}"));
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2).ok_or_else(invalid)?;
+            // `from_str_radix` would also take a sign, like in `%+1`
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return Err(invalid());
+            }
+            let hex = std::str::from_utf8(hex).expect("hex digits are ASCII");
+            bytes.push(u8::from_str_radix(hex, 16).expect("checked for hex digits"));
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
         }
-        _ => Err(error::Error::BuiltinFunctionError(
-            "get function expects a string or an integer as the second argument".to_string(),
-        )),
     }
+    String::from_utf8(bytes).map_err(|_| invalid())
 }
 
-fn assoc(mut args: Vec<SValue>) -> error::Result<SValue> {
+/// The contents of a JSON string literal for the string, without the quotes
+fn json_escape(args: Vec<SValue>) -> error::Result<SValue> {
+    let s = string_arg("json_escape", args)?;
+    let quoted = serde_json::Value::String(s).to_string();
+    Ok(SValue::new(Value::String(
+        quoted[1..quoted.len() - 1].to_string(),
+    )))
+}
+
+/// The inverse of [`json_escape`]
+fn json_unescape(args: Vec<SValue>) -> error::Result<SValue> {
+    let s = string_arg("json_unescape", args)?;
+    let unescaped: String = serde_json::from_str(&format!("\"{s}\"")).map_err(|e| {
+        error::Error::BuiltinFunctionError(format!("invalid JSON string escapes: {e}"))
+    })?;
+    Ok(SValue::new(Value::String(unescaped)))
+}
+
+fn upper(args: Vec<SValue>) -> error::Result<SValue> {
+    map_string("upper", args, str::to_uppercase)
+}
+
+fn lower(args: Vec<SValue>) -> error::Result<SValue> {
+    map_string("lower", args, str::to_lowercase)
+}
+
+/// Strips leading and trailing Unicode whitespace
+fn trim(args: Vec<SValue>) -> error::Result<SValue> {
+    map_string("trim", args, |s| s.trim().to_string())
+}
+
+/// Replace every non-overlapping occurrence of the needle, like `str::replace`
+fn replace(mut args: Vec<SValue>) -> error::Result<SValue> {
     assert!(
         args.len() == 3,
-        "assoc function expects exactly three arguments"
+        "replace function expects exactly three arguments"
     );
-    let value = args.remove(2);
-    let key = args.remove(1);
-    let container = args.remove(0);
+    let replacement = args.remove(2);
+    let needle = args.remove(1);
+    let haystack = args.remove(0);
+    let (Some(haystack), Some(needle), Some(replacement)) = (
+        haystack.as_string(),
+        needle.as_string(),
+        replacement.as_string(),
+    ) else {
+        return Err(error::Error::InvalidType("string"));
+    };
+    if needle.is_empty() {
+        return Err(error::Error::BuiltinFunctionError(
+            "replace function expects a non-empty string to replace".to_string(),
+        ));
+    }
+    Ok(SValue::new(Value::String(
+        haystack.replace(needle, replacement),
+    )))
+}
 
-    match &*key {
-        Value::String(s) => {
-            let Value::Dict(dict) = &*container else {
-                return Err(error::Error::BuiltinFunctionError(format!(
-                    "assoc function expects a dict as the first argument, got {container}",
-                )));
+fn map_string(
+    name: &str,
+    mut args: Vec<SValue>,
+    f: impl Fn(&str) -> String,
+) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "{name} function expects exactly one argument"
+    );
+    let arg = args.remove(0);
+    let s = arg.as_string().ok_or(error::Error::InvalidType("string"))?;
+    Ok(SValue::new(Value::String(f(s))))
+}
+
+fn url_encode(args: Vec<SValue>) -> error::Result<SValue> {
+    let s = string_arg("url_encode", args)?;
+    Ok(SValue::new(Value::String(percent_encode(&s))))
+}
+
+fn url_decode(args: Vec<SValue>) -> error::Result<SValue> {
+    let s = string_arg("url_decode", args)?;
+    Ok(SValue::new(Value::String(percent_decode(&s)?)))
+}
+
+/// Parse `a=1&b=2` into a dict of strings. `+` is decoded as a space
+fn query_to_dict(args: Vec<SValue>) -> error::Result<SValue> {
+    let s = string_arg("query_to_dict", args)?;
+    let mut result = IndexMap::new();
+    for pair in s
+        .trim_start_matches('?')
+        .split('&')
+        .filter(|p| !p.is_empty())
+    {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        result.insert(
+            percent_decode(&k.replace('+', " "))?,
+            SValue::new(Value::String(percent_decode(&v.replace('+', " "))?)),
+        );
+    }
+    Ok(SValue::new(Value::Dict(crate::data::Dict {
+        elements: result.into(),
+        rest: None.into(),
+    })))
+}
+
+/// Build a query string from a dict. Values that aren't strings are written as they are displayed
+fn dict_to_query(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "dict_to_query function expects exactly one argument"
+    );
+    let arg = args.remove(0);
+    let Value::Dict(dict) = &*arg else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "dict_to_query function expects a dict, got {arg}"
+        )));
+    };
+    arg.realize()?;
+    let pairs: Vec<_> = dict
+        .elements
+        .borrow()
+        .iter()
+        .map(|(k, v)| {
+            let v = match &**v {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
             };
-            dict.realize_all()?;
-            // lazy_rest is None, so we can just copy the elements
-            let mut elements = dict.elements.borrow().clone();
-            elements.insert(s.clone(), value);
-            Ok(SValue::new(Value::Dict(crate::data::Dict {
-                elements: elements.into(),
-                rest: None.into(),
-            })))
+            format!("{}={}", percent_encode(k), percent_encode(&v))
+        })
+        .collect();
+    Ok(SValue::new(Value::String(pairs.join("&"))))
+}
+
+/// The value serialized as JSON with sorted keys, so equal values give equal strings regardless
+/// of key order
+pub(crate) fn canonical_json(value: &Value) -> error::Result<String> {
+    fn sort_keys(value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Array(a) => {
+                serde_json::Value::Array(a.into_iter().map(sort_keys).collect())
+            }
+            serde_json::Value::Object(o) => {
+                let mut entries: Vec<_> = o.into_iter().collect();
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                serde_json::Value::Object(
+                    entries
+                        .into_iter()
+                        .map(|(k, v)| (k, sort_keys(v)))
+                        .collect(),
+                )
+            }
+            v => v,
         }
-        Value::Int(n) => {
-            let Value::List(list) = &*container else {
-                return Err(error::Error::BuiltinFunctionError(format!(
-                    "assoc function expects a list as the first argument, got {container}",
-                )));
-            };
-            list.realize_all()?;
-            let mut elements = list.elements.borrow().clone();
-            if let Some(e) = elements.get_mut(*n as usize) {
-                *e = value;
-            } else {
-                return Err(error::Error::BuiltinFunctionError(format!(
-                    "index out of bounds: {n}",
-                )));
+    }
+
+    Ok(sort_keys(serde_json::Value::try_from(value)?).to_string())
+}
+
+/// A string that two realized values share only when no builtin can tell them apart: every value
+/// is tagged with its type, and dict keys and date offsets are kept. `None` for functions.
+pub(crate) fn exact_key(value: &Value) -> Option<String> {
+    let mut key = String::new();
+    write_key(value, &mut key)?;
+    Some(key)
+}
+
+fn write_key(value: &Value, key: &mut String) -> Option<()> {
+    use std::fmt::Write;
+
+    match value {
+        Value::Null => key.push_str("null"),
+        Value::Bool(b) => write!(key, "{b}").unwrap(),
+        Value::Int(n) => write!(key, "int {n}").unwrap(),
+        Value::BigInt(digits) => write!(key, "bigint {digits}").unwrap(),
+        Value::Float(n) => write!(key, "float {n:?}").unwrap(),
+        Value::Date(d) => write!(key, "date {}", d.to_rfc3339()).unwrap(),
+        Value::String(s) => write!(key, "{s:?}").unwrap(),
+        Value::List(l) => {
+            key.push('[');
+            for e in l.elements.borrow().iter() {
+                write_key(e, key)?;
+                key.push(',');
             }
-            Ok(SValue::new(Value::List(crate::data::List {
-                elements: elements.into(),
-                rest: None.into(),
-            })))
+            key.push(']');
         }
-        _ => Err(error::Error::BuiltinFunctionError(
-            "assoc function expects a string or an integer as the second argument".to_string(),
-        )),
+        Value::Dict(d) => {
+            key.push('{');
+            for (k, v) in d.elements.borrow().iter() {
+                write!(key, "{k:?}:").unwrap();
+                write_key(v, key)?;
+                key.push(',');
+            }
+            key.push('}');
+        }
+        Value::Function(_) => return None,
+    }
+    Some(())
+}
+
+/// `true` if the value matches the JSON Schema, otherwise a list of what doesn't match, each
+/// prefixed with where in the value it is
+fn json_schema_validate(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 2,
+        "json_schema_validate function expects exactly two arguments"
+    );
+    let schema = serde_json::Value::try_from(&*args.remove(1))?;
+    let value = serde_json::Value::try_from(&*args.remove(0))?;
+
+    let validator = jsonschema::validator_for(&schema)
+        .map_err(|e| error::Error::BuiltinFunctionError(format!("invalid JSON schema: {e}")))?;
+    let errors: Vec<_> = validator
+        .iter_errors(&value)
+        .map(|e| SValue::new(Value::String(format!("{}: {e}", e.instance_path()))))
+        .collect();
+    if errors.is_empty() {
+        return Ok(SValue::new(Value::Bool(true)));
     }
+    Ok(SValue::new(Value::List(List {
+        elements: errors.into(),
+        rest: None.into(),
+    })))
+}
+
+/// Hex digest of the value serialized as JSON with sorted keys, so it doesn't depend on key order
+fn hash(mut args: Vec<SValue>) -> error::Result<SValue> {
+    use sha2::Digest;
+
+    assert!(
+        args.len() == 2,
+        "hash function expects exactly two arguments"
+    );
+    let algorithm = args.remove(1);
+    let value = args.remove(0);
+
+    let canonical = canonical_json(&value)?;
+    let digest: Vec<u8> = match algorithm.as_string() {
+        Some("sha256") => sha2::Sha256::digest(&canonical).to_vec(),
+        Some("sha512") => sha2::Sha512::digest(&canonical).to_vec(),
+        Some("md5") => md5::Md5::digest(&canonical).to_vec(),
+        _ => {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "hash function expects one of \"sha256\", \"sha512\" or \"md5\", got {algorithm}"
+            )))
+        }
+    };
+    Ok(SValue::new(Value::String(
+        digest.iter().map(|b| format!("{b:02x}")).collect(),
+    )))
+}
+
+fn json(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "json function expects exactly one argument"
+    );
+    let arg = args.remove(0);
+    let Value::String(s) = &*arg else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "json function expects a string, got {:?}",
+            arg
+        )));
+    };
+
+    let parsed: serde_json::Value = serde_json::from_str(s)
+        .map_err(|e| error::Error::BuiltinFunctionError(format!("failed to parse JSON: {}", e)))?;
+
+    Ok(SValue::new(Value::from(parsed)))
+}
+
+/// The inverse of [`json`]. With an indent, the JSON is pretty-printed with that many spaces.
+fn to_json(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1 || args.len() == 2,
+        "to_json function expects one or two arguments"
+    );
+    let indent = match args.get(1).map(|indent| &**indent) {
+        None => None,
+        Some(Value::Int(n)) if *n >= 0 => Some(*n as usize),
+        Some(indent) => {
+            return Err(error::Error::BuiltinFunctionError(format!(
+                "to_json function expects a non-negative integer indent, got {indent}"
+            )))
+        }
+    };
+    let value = serde_json::Value::try_from(&*args.remove(0))?;
+
+    let Some(indent) = indent else {
+        return Ok(SValue::new(Value::String(value.to_string())));
+    };
+    let indent = " ".repeat(indent);
+    let mut out = vec![];
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+    serde::Serialize::serialize(
+        &value,
+        &mut serde_json::Serializer::with_formatter(&mut out, formatter),
+    )
+    .expect("serializing JSON into memory can't fail");
+    Ok(SValue::new(Value::String(
+        String::from_utf8(out).expect("serde_json writes UTF-8"),
+    )))
+}
+
+fn yaml(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "yaml function expects exactly one argument"
+    );
+    let arg = args.remove(0);
+    let Value::String(s) = &*arg else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "yaml function expects a string, got {:?}",
+            arg
+        )));
+    };
+
+    let parsed: serde_json::Value = serde_yaml::from_str(s)
+        .map_err(|e| error::Error::BuiltinFunctionError(format!("failed to parse YAML: {}", e)))?;
+
+    Ok(SValue::new(Value::from(parsed)))
+}
+
+/// Like `json`, but also accepts comments, trailing commas, unquoted keys and single quotes
+fn json5(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "json5 function expects exactly one argument"
+    );
+    let arg = args.remove(0);
+    let Value::String(s) = &*arg else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "json5 function expects a string, got {:?}",
+            arg
+        )));
+    };
+
+    let parsed: serde_json::Value = json5::from_str(s)
+        .map_err(|e| error::Error::BuiltinFunctionError(format!("failed to parse JSON5: {}", e)))?;
+
+    Ok(SValue::new(Value::from(parsed)))
+}
+
+/// Lazily parse rows into dicts keyed by the header row, keeping all fields as strings
+fn from_csv(mut args: Vec<SValue>) -> error::Result<SValue> {
+    assert!(
+        args.len() == 1,
+        "from_csv function expects exactly one argument"
+    );
+    let arg = args.remove(0);
+    let Value::String(s) = &*arg else {
+        return Err(error::Error::BuiltinFunctionError(format!(
+            "from_csv function expects a string, got {:?}",
+            arg
+        )));
+    };
+
+    let mut reader = csv::Reader::from_reader(std::io::Cursor::new(s.clone()));
+    let headers = reader
+        .headers()
+        .map_err(|e| error::Error::BuiltinFunctionError(format!("failed to parse CSV: {}", e)))?
+        .clone();
+    let rows = reader.into_records().map(move |record| {
+        let record = record.map_err(|e| {
+            error::Error::BuiltinFunctionError(format!("failed to parse CSV: {}", e))
+        })?;
+        let vals: IndexMap<_, _> = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(k, v)| (k.to_string(), SValue::new(Value::String(v.to_string()))))
+            .collect();
+        Ok(SValue::new(Value::Dict(crate::data::Dict {
+            elements: vals.into(),
+            rest: None.into(),
+        })))
+    });
+
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new(rows) as BoxedRest<_>).into(),
+    })))
 }
 
-fn json(mut args: Vec<SValue>) -> error::Result<SValue> {
+/// Lazily parse each non-empty line as JSON
+fn from_ndjson(mut args: Vec<SValue>) -> error::Result<SValue> {
     assert!(
         args.len() == 1,
-        "json function expects exactly one argument"
+        "from_ndjson function expects exactly one argument"
     );
     let arg = args.remove(0);
     let Value::String(s) = &*arg else {
         return Err(error::Error::BuiltinFunctionError(format!(
-            "json function expects a string, got {:?}",
+            "from_ndjson function expects a string, got {:?}",
             arg
         )));
     };
 
-    let parsed: serde_json::Value = serde_json::from_str(s)
-        .map_err(|e| error::Error::BuiltinFunctionError(format!("failed to parse JSON: {}", e)))?;
+    let lines: Vec<_> = s
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(String::from)
+        .collect();
+    let values = lines.into_iter().map(|line| {
+        let parsed: serde_json::Value = serde_json::from_str(&line).map_err(|e| {
+            error::Error::BuiltinFunctionError(format!("failed to parse JSON: {}", e))
+        })?;
+        Ok(SValue::new(Value::from(parsed)))
+    });
 
-    Ok(SValue::new(Value::from(parsed)))
+    Ok(SValue::new(Value::List(List {
+        elements: vec![].into(),
+        rest: Some(Box::new(values) as BoxedRest<_>).into(),
+    })))
 }
 
 impl From<serde_json::Value> for Value {
@@ -157,12 +3172,20 @@ impl From<serde_json::Value> for Value {
             serde_json::Value::Null => Value::Null,
             serde_json::Value::Bool(b) => Value::Bool(b),
             serde_json::Value::Number(n) => {
-                if let Some(n) = n.as_u64() {
+                let digits = n.to_string();
+                let is_integer = digits
+                    .strip_prefix('-')
+                    .unwrap_or(&digits)
+                    .bytes()
+                    .all(|b| b.is_ascii_digit());
+                if let Some(n) = n.as_i64() {
                     Value::Int(n)
-                } else if let Some(n) = n.as_f64() {
-                    Value::Float(n)
+                } else if is_integer {
+                    // Outside of i64, keep the digits instead of rounding to a float
+                    Value::BigInt(digits)
                 } else {
-                    panic!("failed to convert JSON number {:?} to u32 or f32", n)
+                    // Floats out of f64's range, like `1e400`, become infinities
+                    Value::Float(digits.parse().expect("JSON numbers are valid floats"))
                 }
             }
             serde_json::Value::String(s) => Value::String(s),
@@ -189,3 +3212,1397 @@ impl From<serde_json::Value> for Value {
         }
     }
 }
+
+impl TryFrom<&Value> for serde_json::Value {
+    type Error = error::Error;
+
+    /// Realizes the value fully. Non-finite floats become `null`, like in javascript.
+    fn try_from(v: &Value) -> error::Result<Self> {
+        Ok(match v {
+            Value::Null => serde_json::Value::Null,
+            Value::Bool(b) => serde_json::Value::Bool(*b),
+            Value::Int(n) => serde_json::Value::Number((*n).into()),
+            Value::BigInt(digits) => serde_json::Value::Number(
+                digits
+                    .parse()
+                    .expect("big integers always hold valid digits"),
+            ),
+            Value::Float(n) => serde_json::Number::from_f64(*n)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            Value::Date(d) => serde_json::Value::String(d.to_rfc3339()),
+            Value::String(s) => serde_json::Value::String(s.clone()),
+            Value::List(l) => {
+                l.realize_all()?;
+                serde_json::Value::Array(
+                    l.elements
+                        .borrow()
+                        .iter()
+                        .map(|e| serde_json::Value::try_from(&**e))
+                        .collect::<error::Result<_>>()?,
+                )
+            }
+            Value::Dict(d) => {
+                d.realize_all()?;
+                serde_json::Value::Object(
+                    d.elements
+                        .borrow()
+                        .iter()
+                        .map(|(k, v)| Ok((k.clone(), serde_json::Value::try_from(&**v)?)))
+                        .collect::<error::Result<_>>()?,
+                )
+            }
+            Value::Function(f) => {
+                return Err(error::Error::BuiltinFunctionError(format!(
+                    "cannot convert function {} to JSON",
+                    f.name
+                )))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::interpreter::Interpreter;
+    use crate::parser::command;
+
+    use super::*;
+
+    fn eval(input: &str, cmd: &str) -> SValue {
+        let mut interpreter = Interpreter::new(input.into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter.run(command(cmd).unwrap()).unwrap();
+        interpreter.value()
+    }
+
+    /// Like [`eval`], for a command that has to fail
+    fn eval_err(input: &str, cmd: &str) -> error::Error {
+        let mut interpreter = Interpreter::new(input.into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter.run(command(cmd).unwrap()).unwrap_err()
+    }
+
+    #[test]
+    fn test_sum_avg_by() {
+        let records = r#"[{"price": 1.5}, {"price": 2}, {"price": 4.5}]"#;
+        assert_eq!(
+            &*eval(records, r#"sum_by |r| get r "price""#),
+            &Value::Float(8.0)
+        );
+        assert_eq!(
+            &*eval(records, r#"avg_by |r| get r "price""#),
+            &Value::Float(8.0 / 3.0)
+        );
+        let counts = r#"[{"n": 1}, {"n": 2}, {"n": 4}]"#;
+        assert_eq!(&*eval(counts, r#"sum_by |r| get r "n""#), &Value::Int(7));
+        assert_eq!(&*eval("[]", r#"sum_by |r| get r "n""#), &Value::Int(0));
+
+        eval_err("[]", r#"avg_by |r| get r "price""#);
+
+        eval_err(r#"[{"price": "free"}]"#, r#"sum_by |r| get r "price""#);
+
+        // A key function taking the wrong number of arguments isn't called with some unbound
+        assert!(matches!(
+            eval_err(records, "sum_by |a, b| a"),
+            error::Error::InvalidArity(_, 1, _)
+        ));
+    }
+
+    #[test]
+    fn test_sorted() {
+        let value = eval(r#"{"b": 1, "a": 2}"#, "sorted");
+        let keys: Vec<_> = value
+            .as_dict()
+            .unwrap()
+            .elements
+            .borrow()
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(keys, vec!["a", "b"]);
+
+        assert_eq!(
+            &*eval("[3, 1.5, 2]", "sorted"),
+            &Value::List(List {
+                elements: vec![
+                    SValue::new(Value::Float(1.5)),
+                    SValue::new(Value::Int(2)),
+                    SValue::new(Value::Int(3)),
+                ]
+                .into(),
+                rest: None.into(),
+            })
+        );
+
+        eval_err(r#"[1, "a"]"#, "sorted");
+    }
+
+    #[test]
+    fn test_reverse() {
+        let value = eval("[1, 2, 3]", "reverse");
+        assert_eq!(value.to_string(), "[3, 2, 1]");
+        assert_eq!(
+            &*eval(r#""abcé""#, "reverse"),
+            &Value::String("écba".into())
+        );
+
+        let lazy = eval("[]", "reverse (take (cycle [1, 2]) 3)");
+        assert_eq!(lazy.to_string(), "[1, 2, 1]");
+
+        eval_err(r#"{"a": 1}"#, "reverse");
+        eval_err(r#"{"a": 1}"#, "reverse 1");
+    }
+
+    #[test]
+    fn test_only() {
+        assert_eq!(&*eval("[5]", "only"), &Value::Int(5));
+        assert_eq!(&*eval(r#"{"a": "x"}"#, "only"), &Value::String("x".into()));
+
+        for input in ["[]", "{}", "[1, 2, 3]", r#"{"a": 1, "b": 2}"#] {
+            eval_err(input, "only");
+        }
+
+        let list = SValue::new(Value::List(List {
+            elements: vec![].into(),
+            rest: Some(Box::new((0..).map(|n| Ok(SValue::new(Value::Int(n))))) as BoxedRest<_>)
+                .into(),
+        }));
+        assert!(only(vec![list.clone()]).is_err());
+        assert_eq!(list.as_list().unwrap().elements.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_map() {
+        let value = eval(r#"{"a": 1, "b": 2}"#, "map |x| x * 2");
+        value.realize().unwrap();
+        let dict = value.as_dict().unwrap().elements.borrow();
+        assert_eq!(
+            dict.iter()
+                .map(|(k, v)| (k.as_str(), v.as_number().unwrap()))
+                .collect::<Vec<_>>(),
+            vec![("a", 2.0), ("b", 4.0)]
+        );
+
+        let value = eval("[1, 2, 3]", "map |x| x + 1");
+        value.realize().unwrap();
+        assert_eq!(
+            &*value,
+            &Value::List(List {
+                elements: vec![
+                    SValue::new(Value::Int(2)),
+                    SValue::new(Value::Int(3)),
+                    SValue::new(Value::Int(4)),
+                ]
+                .into(),
+                rest: None.into(),
+            })
+        );
+
+        // An expression is evaluated with `%` as the element
+        let value = eval("[1, 2, 3]", "map (% + 1)");
+        value.realize().unwrap();
+        assert_eq!(value.to_string(), "[2, 3, 4]");
+        let value = eval(r#"{"a": [1, 2], "b": []}"#, "map (len %)");
+        value.realize().unwrap();
+        assert_eq!(value.to_string(), "{a: 2, b: 0}");
+        let value = eval("[1, 2]", "map % 0");
+        value.realize().unwrap();
+        assert_eq!(value.to_string(), "[0, 0]");
+
+        // Only the elements that are asked for get computed
+        let source = SValue::new(Value::List(List {
+            elements: vec![].into(),
+            rest: Some(Box::new((0..).map(|n| Ok(SValue::new(Value::Int(n))))) as BoxedRest<_>)
+                .into(),
+        }));
+        let value = map(vec![source.clone(), eval("null", "|x| x * 10")]).unwrap();
+        value.as_list().unwrap().realize_n(3).unwrap();
+        assert_eq!(value.sampled(3).to_string(), "[0, 10, 20, ...]");
+        assert_eq!(source.as_list().unwrap().elements.borrow().len(), 3);
+
+        assert!(map(vec![eval("[1]", "%"), eval("null", "1")]).is_err());
+    }
+
+    #[test]
+    fn test_first_last_key() {
+        let dict = r#"{"z": 1, "a": 2, "m": 3}"#;
+        assert_eq!(&*eval(dict, "first_key"), &Value::String("z".into()));
+        assert_eq!(&*eval(dict, "last_key"), &Value::String("m".into()));
+        assert_eq!(&*eval("{}", "first_key"), &Value::Null);
+        assert_eq!(&*eval("{}", "last_key"), &Value::Null);
+    }
+
+    #[test]
+    fn test_loaders() {
+        let mut interpreter = Interpreter::new("a: 1\nb: [x, y]\n".into());
+        interpreter.run(command("yaml").unwrap()).unwrap();
+        assert_eq!(interpreter.value().to_string(), r#"{a: 1, b: ["x", "y"]}"#);
+
+        let mut interpreter = Interpreter::new(
+            "// settings\n{unquoted: 'single', list: [1.5, 2,], /* inline */ n: null,}".into(),
+        );
+        interpreter.run(command("json5").unwrap()).unwrap();
+        assert_eq!(
+            interpreter.value().to_string(),
+            r#"{unquoted: "single", list: [1.5, 2], n: null}"#
+        );
+        let mut interpreter = Interpreter::new("{a: }".into());
+        assert!(interpreter.run(command("json5").unwrap()).is_err());
+
+        let mut interpreter = Interpreter::new("{\"a\": 1}\n\n[2]\n".into());
+        interpreter.run(command("from_ndjson").unwrap()).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(interpreter.value().to_string(), "[{a: 1}, [2]]");
+
+        let mut interpreter = Interpreter::new("a,b\n1,2\n".into());
+        interpreter.run(command("from_csv").unwrap()).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(interpreter.value().to_string(), r#"[{a: "1", b: "2"}]"#);
+    }
+
+    #[test]
+    fn test_big_integers() {
+        let value = eval(
+            "[98765432109876543210, -98765432109876543210, -9223372036854775807]",
+            "%",
+        );
+        assert_eq!(
+            value.to_string(),
+            "[98765432109876543210, -98765432109876543210, -9223372036854775807]"
+        );
+        assert_eq!(
+            &*value.as_list().unwrap().get(0).unwrap().unwrap(),
+            &Value::BigInt("98765432109876543210".into())
+        );
+        assert_eq!(
+            serde_json::Value::try_from(&*value).unwrap().to_string(),
+            "[98765432109876543210,-98765432109876543210,-9223372036854775807]"
+        );
+
+        let huge = eval("[1e400, -1e400]", "%");
+        let huge = huge.as_list().unwrap().elements.borrow();
+        assert_eq!(&*huge[0], &Value::Float(f64::INFINITY));
+        assert_eq!(&*huge[1], &Value::Float(f64::NEG_INFINITY));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let nested =
+            r#"{"a": [1, -2.5, null, true], "b": {"c": "d\"e", "big": 98765432109876543210}}"#;
+        let json = eval(nested, "to_json");
+        assert_eq!(
+            &*json,
+            &Value::String(
+                r#"{"a":[1,-2.5,null,true],"b":{"c":"d\"e","big":98765432109876543210}}"#.into()
+            )
+        );
+        let round_trip = eval(nested, "json (to_json %)");
+        round_trip.realize().unwrap();
+        assert_eq!(round_trip.to_string(), eval(nested, "%").to_string());
+
+        assert_eq!(
+            &*eval(r#"{"a": [1]}"#, "to_json % 2"),
+            &Value::String("{\n  \"a\": [\n    1\n  ]\n}".into())
+        );
+        // Lazy values are realized first
+        assert_eq!(
+            &*eval("null", "to_json (range 3)"),
+            &Value::String("[0,1,2]".into())
+        );
+
+        let mut interpreter = Interpreter::new("[1]".into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        assert!(interpreter.run(command("to_json |x| x").unwrap()).is_err());
+        match interpreter.run(command("to_json % (-1)").unwrap()) {
+            Err(error::Error::BuiltinFunctionError(message)) => {
+                assert!(message.contains("non-negative integer indent"), "{message}")
+            }
+            result => panic!("expected an indent error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn test_negative_integers() {
+        let value = eval("[-5, -9223372036854775808, -1.5]", "%");
+        assert_eq!(
+            &*value.as_list().unwrap().get(0).unwrap().unwrap(),
+            &Value::Int(-5)
+        );
+        assert_eq!(
+            &*value.as_list().unwrap().get(1).unwrap().unwrap(),
+            &Value::Int(i64::MIN)
+        );
+        assert_eq!(
+            serde_json::Value::try_from(&*value).unwrap().to_string(),
+            "[-5,-9223372036854775808,-1.5]"
+        );
+    }
+
+    #[test]
+    fn test_first_last() {
+        assert_eq!(&*eval("[1, 2, 3]", "first"), &Value::Int(1));
+        assert_eq!(&*eval("[1, 2, 3]", "last"), &Value::Int(3));
+        assert_eq!(
+            &*eval("null", "first (range 9223372036854775807)"),
+            &Value::Int(0)
+        );
+
+        for f in ["first", "last"] {
+            assert!(matches!(
+                eval_err("[]", f),
+                error::Error::BuiltinFunctionError(_)
+            ));
+        }
+        for f in ["first", "last"] {
+            assert!(matches!(
+                eval_err(r#"{"a": 1}"#, f),
+                error::Error::InvalidType("list")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_group_by() {
+        let people = r#"[
+            {"name": "ann", "city": "oslo"},
+            {"name": "bob", "city": "rome"},
+            {"name": "cat", "city": "oslo"}
+        ]"#;
+        let groups = eval(people, r#"group_by |p| get p "city""#);
+        assert_eq!(
+            groups.to_string(),
+            r#"{oslo: [{name: "ann", city: "oslo"}, {name: "cat", city: "oslo"}], rome: [{name: "bob", city: "rome"}]}"#
+        );
+        assert_eq!(eval("[]", "group_by |x| x").to_string(), "{}");
+
+        assert!(matches!(
+            eval_err("[1, 2]", "group_by |x| x"),
+            error::Error::InvalidType("string")
+        ));
+    }
+
+    #[test]
+    fn test_lazy_group_by() {
+        let source = SValue::new(Value::List(List {
+            elements: vec![].into(),
+            rest: Some(Box::new(
+                ["a", "a", "b", "c", "c"]
+                    .into_iter()
+                    .map(|s| Ok(SValue::new(Value::String(s.into())))),
+            ) as BoxedRest<_>)
+            .into(),
+        }));
+        let identity = SValue::new(Value::Function(Function {
+            name: "identity".into(),
+            arities: vec![1],
+            implementation: Box::new(|mut args| Ok(args.remove(0))),
+        }));
+
+        let groups = lazy_group_by(vec![source.clone(), identity]).unwrap();
+        let (key, group) = groups.as_dict().unwrap().get_first().unwrap().unwrap();
+        assert_eq!(key, "a");
+        assert_eq!(group.to_string(), r#"["a", "a"]"#);
+        // The first group, and the element that ended it
+        assert_eq!(source.as_list().unwrap().elements.borrow().len(), 3);
+
+        groups.realize().unwrap();
+        assert_eq!(
+            groups.to_string(),
+            r#"{a: ["a", "a"], b: ["b"], c: ["c", "c"]}"#
+        );
+    }
+
+    #[test]
+    fn test_failing_element_stays() {
+        let failing = || {
+            vec![
+                Ok(1),
+                Err(error::Error::BuiltinFunctionError("bad".into())),
+                Ok(3),
+            ]
+            .into_iter()
+        };
+        let list = List {
+            elements: vec![].into(),
+            rest: Some(
+                Box::new(failing().map(|n| n.map(|n| SValue::new(Value::Int(n))))) as BoxedRest<_>,
+            )
+            .into(),
+        };
+        assert!(list.realize_all().is_err());
+        assert!(list.realize_all().is_err());
+        assert!(list.get(1).is_err());
+        assert_eq!(list.elements.borrow().len(), 1);
+
+        let dict = crate::data::Dict {
+            elements: IndexMap::new().into(),
+            rest: Some(Box::new(
+                failing().map(|n| n.map(|n| (n.to_string(), SValue::new(Value::Int(n))))),
+            ) as BoxedRest<_>)
+            .into(),
+        };
+        assert!(dict.get("3").is_err());
+        assert!(dict.realize_all().is_err());
+        assert!(dict.get_nth(1).is_err());
+        assert_eq!(dict.elements.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_realize_reserves() {
+        let source = SValue::new(Value::List(List {
+            elements: vec![SValue::new(Value::Int(0))].into(),
+            rest: Some(Box::new((1..1000).map(|n| Ok(SValue::new(Value::Int(n))))) as BoxedRest<_>)
+                .into(),
+        }));
+        let iter = List::into_iter(source.clone());
+        assert_eq!(iter.size_hint(), (1000, Some(1000)));
+
+        let copy = List {
+            elements: vec![].into(),
+            rest: Some(Box::new(iter) as BoxedRest<_>).into(),
+        };
+        copy.realize_all().unwrap();
+        assert!(copy.elements.borrow().capacity() >= 1000);
+        assert_eq!(copy.elements, source.as_list().unwrap().elements);
+        assert_eq!(copy.elements.borrow().len(), 1000);
+
+        // Partway through `realize_all` the rest is out of the list, so the size isn't known
+        let pending = SValue::new(Value::List(List {
+            elements: vec![SValue::new(Value::Int(0))].into(),
+            rest: Some(Box::new((1..1000).map(|n| Ok(SValue::new(Value::Int(n))))) as BoxedRest<_>)
+                .into(),
+        }));
+        let iter = List::into_iter(pending.clone());
+        let list = pending.as_list().unwrap();
+        let rest = list.rest.take();
+        let elements = list.elements.borrow_mut();
+        assert_eq!(iter.size_hint(), (0, None));
+        drop((elements, rest));
+    }
+
+    #[test]
+    fn test_assoc_keeps_order() {
+        let value = eval(
+            r#"{"c": 1, "a": 2, "b": 3}"#,
+            r#"assoc (assoc (assoc (assoc % "a" 20) "z" 4) "c" 10) "y" 5"#,
+        );
+        assert_eq!(value.to_string(), "{c: 10, a: 20, b: 3, z: 4, y: 5}");
+
+        let value = eval(r#"{"c": 1, "a": 2}"#, r#"map (assoc % "b" 3) |x| x"#);
+        value.realize().unwrap();
+        assert_eq!(value.to_string(), "{c: 1, a: 2, b: 3}");
+    }
+
+    #[test]
+    fn test_update() {
+        let record = r#"{"n": 1, "inner": {"tags": ["a"]}}"#;
+        assert_eq!(
+            eval(record, r#"update % "n" |x| x + 1"#).to_string(),
+            r#"{n: 2, inner: {tags: ["a"]}}"#
+        );
+        assert_eq!(
+            eval(
+                record,
+                r#"update % "inner" |i| update i "tags" |t| assoc t 0 "b""#
+            )
+            .to_string(),
+            r#"{n: 1, inner: {tags: ["b"]}}"#
+        );
+        assert_eq!(
+            eval(record, r#"update % "missing" |x| x == null"#).to_string(),
+            r#"{n: 1, inner: {tags: ["a"]}, missing: true}"#
+        );
+        assert_eq!(
+            eval("[1, 2]", "update % 1 |x| x * 10").to_string(),
+            "[1, 20]"
+        );
+
+        eval_err("[1, 2]", "update % 2 |x| x");
+    }
+
+    #[test]
+    fn test_dissoc() {
+        let dict = r#"{"c": 1, "a": 2, "b": 3}"#;
+        assert_eq!(eval(dict, r#"dissoc "a""#).to_string(), "{c: 1, b: 3}");
+        assert_eq!(&*eval(dict, r#"dissoc "z""#), &*eval(dict, "%"));
+
+        assert_eq!(eval("[1, 2, 3]", "dissoc 0").to_string(), "[2, 3]");
+        assert_eq!(eval("[1, 2, 3]", "dissoc 2").to_string(), "[1, 2]");
+
+        eval_err("[1, 2, 3]", "dissoc 3");
+        eval_err("[1, 2, 3]", r#"dissoc "a""#);
+    }
+
+    #[test]
+    fn test_type() {
+        for (input, expected) in [
+            ("null", "null"),
+            ("true", "bool"),
+            ("1", "int"),
+            ("123456789012345678901234567890", "int"),
+            ("1.5", "float"),
+            (r#""a""#, "string"),
+            ("[1]", "list"),
+            (r#"{"a": 1}"#, "dict"),
+        ] {
+            assert_eq!(&*eval(input, "type %"), &Value::String(expected.into()));
+        }
+        assert_eq!(
+            &*eval("null", "type |x| x"),
+            &Value::String("function".into())
+        );
+        assert_eq!(
+            &*eval(r#""2024-02-29""#, r#"type (parse_date % "%Y-%m-%d")"#),
+            &Value::String("date".into())
+        );
+    }
+
+    #[test]
+    fn test_math() {
+        assert_eq!(&*eval("3", "abs"), &Value::Int(3));
+        assert_eq!(&*eval("-2.5", "abs"), &Value::Float(2.5));
+        assert_eq!(
+            &*eval("-98765432109876543210", "abs"),
+            &Value::BigInt("98765432109876543210".into())
+        );
+
+        assert_eq!(&*eval("2.7", "floor"), &Value::Int(2));
+        assert_eq!(&*eval("2.2", "ceil"), &Value::Int(3));
+        assert_eq!(&*eval("2.5", "round"), &Value::Int(3));
+        assert_eq!(&*eval("7", "round"), &Value::Int(7));
+        assert_eq!(&*eval("-2.5", "floor"), &Value::Int(-3));
+        assert_eq!(&*eval("-2.5", "ceil"), &Value::Int(-2));
+        assert_eq!(&*eval("-0.4", "round"), &Value::Int(0));
+        assert_eq!(&*eval("1e30", "floor"), &Value::Float(1e30));
+
+        assert_eq!(&*eval("6.25", "sqrt"), &Value::Float(2.5));
+        assert_eq!(&*eval("4", "sqrt"), &Value::Float(2.0));
+
+        assert!(matches!(
+            eval_err("-4", "sqrt"),
+            error::Error::BuiltinFunctionError(_)
+        ));
+        for f in ["abs", "floor", "ceil", "round", "sqrt"] {
+            assert!(matches!(
+                eval_err(r#""1""#, f),
+                error::Error::InvalidType("number")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_sum_min_max() {
+        assert_eq!(&*eval("[1, 2, 3]", "sum"), &Value::Int(6));
+        assert_eq!(&*eval("[1, 2.5]", "sum"), &Value::Float(3.5));
+        assert_eq!(
+            &*eval("[9223372036854775807, 1]", "sum"),
+            &Value::Float(9223372036854775808.0)
+        );
+        assert_eq!(&*eval("[3, 1.5, 2]", "min"), &Value::Float(1.5));
+        assert_eq!(&*eval("[3, 1.5, 2]", "max"), &Value::Int(3));
+        assert_eq!(&*eval("[2, 2.0]", "max"), &Value::Int(2));
+        assert_eq!(&*eval("[1]", "min"), &Value::Int(1));
+
+        for f in ["sum", "min", "max"] {
+            assert!(matches!(
+                eval_err("[]", f),
+                error::Error::BuiltinFunctionError(_)
+            ));
+        }
+        for f in ["sum", "min", "max"] {
+            assert!(matches!(
+                eval_err(r#"[1, "2"]"#, f),
+                error::Error::InvalidType("number")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_has() {
+        let dict = r#"{"a": 1, "b": null}"#;
+        assert_eq!(eval(dict, r#"has "a""#).to_string(), "true");
+        assert_eq!(eval(dict, r#"has "b""#).to_string(), "true");
+        assert_eq!(eval(dict, r#"has "z""#).to_string(), "false");
+
+        assert_eq!(eval("[1, 2, 3]", "has 0").to_string(), "true");
+        assert_eq!(eval("[1, 2, 3]", "has 2").to_string(), "true");
+        assert_eq!(eval("[1, 2, 3]", "has 3").to_string(), "false");
+        // Only realized up to the index
+        assert_eq!(
+            eval("null", "has (range 1000000000000) 5").to_string(),
+            "true"
+        );
+
+        eval_err("[1, 2, 3]", r#"has "a""#);
+    }
+
+    #[test]
+    fn test_deep_equal_ordered() {
+        let dict = r#"{"a": 1, "b": 2}"#;
+        assert_eq!(eval(dict, r#"% == {"b": 2, "a": 1}"#).to_string(), "true");
+        assert_eq!(
+            eval(dict, r#"deep_equal_ordered % {"b": 2, "a": 1}"#).to_string(),
+            "false"
+        );
+        assert_eq!(
+            eval(dict, r#"deep_equal_ordered % {"a": 1, "b": 2.0}"#).to_string(),
+            "true"
+        );
+        assert_eq!(
+            eval(dict, r#"deep_equal_ordered [%] [{"b": 2, "a": 1}]"#).to_string(),
+            "false"
+        );
+        assert_eq!(
+            eval(dict, r#"deep_equal_ordered {"x": %} {"x": %}"#).to_string(),
+            "true"
+        );
+        assert_eq!(
+            eval(dict, "deep_equal_ordered % [1, 2]").to_string(),
+            "false"
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let dict = r#"{"c": 1, "a": 2}"#;
+        assert_eq!(
+            eval(dict, r#"merge % {"a": 20, "b": 3}"#).to_string(),
+            "{c: 1, a: 20, b: 3}"
+        );
+        assert_eq!(
+            eval(dict, r#"merge % {"z": 1, "y": 2}"#).to_string(),
+            "{c: 1, a: 2, z: 1, y: 2}"
+        );
+        assert_eq!(
+            eval(dict, r#"merge {"a": 0, "y": 2} %"#).to_string(),
+            "{a: 2, y: 2, c: 1}"
+        );
+
+        eval_err(dict, "merge % [1]");
+        eval_err(dict, "merge 1 %");
+    }
+
+    #[test]
+    fn test_flatten_dict() {
+        let nested = r#"{"a": {"b": 1, "c": [2, {"d": 3}]}, "e": {}}"#;
+        let flat = eval(nested, "flatten_dict");
+        assert_eq!(flat.to_string(), "{a.b: 1, a.c[0]: 2, a.c[1].d: 3, e: {}}");
+
+        let roundtrip = eval(nested, "unflatten_dict (flatten_dict %)");
+        assert_eq!(roundtrip.to_string(), "{a: {b: 1, c: [2, {d: 3}]}, e: {}}");
+
+        eval_err(r#"{"a": 1, "a.b": 2}"#, "unflatten_dict");
+    }
+
+    #[test]
+    fn test_query() {
+        let records =
+            r#"{"users": [{"name": "a", "tags": ["x"]}, {"name": "b", "tags": ["y", "z"]}]}"#;
+        assert_eq!(
+            eval(records, r#"query % ["users", "*", "name"]"#).to_string(),
+            r#"["a", "b"]"#
+        );
+        assert_eq!(
+            eval(records, r#"query % ["users", "*", "tags", "*"]"#).to_string(),
+            r#"["x", "y", "z"]"#
+        );
+        assert_eq!(
+            eval(records, r#"query % ["users", 1, "name"]"#).to_string(),
+            r#""b""#
+        );
+        assert_eq!(
+            eval(r#"{"a": {"n": 1}, "b": {"n": 2}}"#, r#"query % ["*", "n"]"#).to_string(),
+            "[1, 2]"
+        );
+    }
+
+    #[test]
+    fn test_zip_to_dict() {
+        let table = r#"{"header": ["b", "a"], "row": [1, 2], "short": [3], "ints": [1, 2]}"#;
+        assert_eq!(
+            eval(table, r#"zip_to_dict (get % "header") (get % "row")"#).to_string(),
+            "{b: 1, a: 2}"
+        );
+        assert_eq!(
+            eval(table, r#"zip_to_dict (get % "header") (get % "short")"#).to_string(),
+            "{b: 3}"
+        );
+
+        eval_err(table, r#"zip_to_dict (get % "ints") (get % "row")"#);
+    }
+
+    #[test]
+    fn test_to_markdown_table() {
+        let rows = r#"[{"name": "a|b", "n": 1}, {"n": 2, "tags": ["x"]}]"#;
+        let Value::String(table) = &*eval(rows, "to_markdown_table") else {
+            panic!("expected a string");
+        };
+        assert_eq!(
+            table,
+            "| name | n | tags |\n\
+             | --- | --- | --- |\n\
+             | a\\|b | 1 |  |\n\
+             |  | 2 | [\"x\"] |"
+        );
+    }
+
+    #[test]
+    fn test_skip_errors() {
+        let mixed = (0..6).map(|i| {
+            if i % 2 == 0 {
+                Ok(SValue::new(Value::Int(i)))
+            } else {
+                Err(error::Error::BuiltinFunctionError(format!("bad {i}")))
+            }
+        });
+        let list = SValue::new(Value::List(List {
+            elements: vec![].into(),
+            rest: Some(Box::new(mixed) as BoxedRest<_>).into(),
+        }));
+        let skipped = skip_errors(vec![list]).unwrap();
+        skipped.realize().unwrap();
+        assert_eq!(skipped.to_string(), "[0, 2, 4]");
+
+        let mut interpreter = Interpreter::new("{\"a\": 1}\nnope\n{\"a\": 2}".into());
+        interpreter.run(command("from_ndjson").unwrap()).unwrap();
+        interpreter.run(command("skip_errors").unwrap()).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(interpreter.value().to_string(), "[{a: 1}, {a: 2}]");
+    }
+
+    #[test]
+    fn test_describe() {
+        let records = r#"[
+            {"price": 1, "name": "a"},
+            {"price": 2.5, "name": "b"},
+            {"price": null, "name": "a"},
+            {"price": 4.5}
+        ]"#;
+        assert_eq!(
+            eval(records, "describe").to_string(),
+            "{price: {count: 3, min: 1, max: 4.5, mean: 2.6666666666666665}, \
+             name: {count: 3, distinct: 2}}"
+        );
+    }
+
+    #[test]
+    fn test_dates() {
+        let date = eval(r#""2024-03-01 12:30""#, r#"parse_date % "%Y-%m-%d %H:%M""#);
+        assert_eq!(date.to_string(), "2024-03-01T12:30:00+00:00");
+        assert_eq!(
+            &*format_date(vec![date.clone(), eval(r#""%d/%m/%Y""#, "%")]).unwrap(),
+            &Value::String("01/03/2024".into())
+        );
+        assert_eq!(
+            serde_json::Value::try_from(&*date).unwrap(),
+            serde_json::Value::String("2024-03-01T12:30:00+00:00".into())
+        );
+
+        let earlier = eval(r#""2024-02-29""#, r#"parse_date % "%Y-%m-%d""#);
+        assert_eq!(compare(&earlier, &date).unwrap(), std::cmp::Ordering::Less);
+        let same_instant = eval(
+            r#""2024-03-01 14:30 +0200""#,
+            r#"parse_date % "%Y-%m-%d %H:%M %z""#,
+        );
+        assert_eq!(&*same_instant, &*date);
+        assert!(matches!(&*eval("null", "now"), Value::Date(_)));
+
+        eval_err(r#""March 1st""#, r#"parse_date % "%Y-%m-%d""#);
+    }
+
+    #[test]
+    fn test_date_diff() {
+        let date = |s: &str| eval(&format!("{s:?}"), r#"parse_date % "%Y-%m-%d %H:%M:%S""#);
+        let from = date("2024-02-28 12:00:00");
+        let to = date("2024-03-01 12:00:30");
+        let diff = |from: &SValue, to: &SValue, unit: &str| {
+            let unit = SValue::new(Value::String(unit.into()));
+            date_diff(vec![from.clone(), to.clone(), unit])
+        };
+
+        assert_eq!(
+            diff(&from, &to, "seconds").unwrap().as_number(),
+            Some(2.0 * 24.0 * 3600.0 + 30.0)
+        );
+        let days = diff(&from, &to, "days").unwrap().as_number().unwrap();
+        assert!((days - 2.0).abs() < 0.001);
+        assert_eq!(
+            diff(&to, &from, "seconds").unwrap().as_number(),
+            Some(-(2.0 * 24.0 * 3600.0 + 30.0))
+        );
+        assert!(diff(&from, &to, "fortnights").is_err());
+    }
+
+    #[test]
+    fn test_range() {
+        let value = eval("null", "range 5");
+        value.realize().unwrap();
+        assert_eq!(value.to_string(), "[0, 1, 2, 3, 4]");
+        let value = eval("null", "range 2 6");
+        value.realize().unwrap();
+        assert_eq!(value.to_string(), "[2, 3, 4, 5]");
+        let value = eval("null", "range 6 2");
+        value.realize().unwrap();
+        assert_eq!(value.to_string(), "[]");
+
+        let huge = eval("null", "range 9223372036854775807");
+        huge.as_list().unwrap().realize_n(3).unwrap();
+        assert_eq!(huge.as_list().unwrap().elements.borrow().len(), 3);
+        let big = eval("null", "take (filter (range 1000000000000) |x| x > 2) 2");
+        big.realize().unwrap();
+        assert_eq!(big.to_string(), "[3, 4]");
+
+        eval_err("null", r#"range "5""#);
+        eval_err("null", "range 1.5 3");
+    }
+
+    #[test]
+    fn test_flatten() {
+        let flat = eval("[[1, 2], [3], [], [4, 5]]", "flatten");
+        flat.realize().unwrap();
+        assert_eq!(flat.to_string(), "[1, 2, 3, 4, 5]");
+
+        let nested = eval("[[[1]], [2]]", "flatten");
+        nested.realize().unwrap();
+        assert_eq!(nested.to_string(), "[[1], 2]");
+
+        // Infinite inner lists are only realized as far as needed
+        let lazy = eval("null", "take (flatten [(range 9223372036854775807)]) 3");
+        lazy.realize().unwrap();
+        assert_eq!(lazy.to_string(), "[0, 1, 2]");
+
+        let mixed = eval("[[1], 2]", "flatten");
+        assert!(matches!(
+            mixed.realize().unwrap_err(),
+            error::Error::InvalidType("list")
+        ));
+    }
+
+    #[test]
+    fn test_concat() {
+        let two = eval("[1, 2]", "concat % [3]");
+        two.realize().unwrap();
+        assert_eq!(two.to_string(), "[1, 2, 3]");
+
+        let three = eval("[1]", "concat [] % [2, 3]");
+        three.realize().unwrap();
+        assert_eq!(three.to_string(), "[1, 2, 3]");
+
+        let ranges = eval("null", "concat (range 3) (range 3)");
+        let list = ranges.as_list().unwrap();
+        list.realize_n(4).unwrap();
+        assert_eq!(list.elements.borrow().len(), 4);
+        assert!(!ranges.is_realized());
+        ranges.realize().unwrap();
+        assert_eq!(ranges.to_string(), "[0, 1, 2, 0, 1, 2]");
+
+        eval_err("[1]", "concat % 1");
+    }
+
+    #[test]
+    fn test_cycle() {
+        let cycled = eval("[]", "take (cycle [1, 2]) 5");
+        cycled.realize().unwrap();
+        assert_eq!(cycled.to_string(), "[1, 2, 1, 2, 1]");
+
+        let empty = eval("[]", "cycle %");
+        empty.realize().unwrap();
+        assert_eq!(empty.to_string(), "[]");
+    }
+
+    #[test]
+    fn test_json_schema_validate() {
+        let schema = r#"{
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer", "minimum": 0}
+            },
+            "required": ["name"]
+        }"#;
+        let validate = |document: &str| {
+            json_schema_validate(vec![eval(document, "%"), eval(schema, "%")]).unwrap()
+        };
+
+        assert_eq!(
+            &*validate(r#"{"name": "ann", "age": 30}"#),
+            &Value::Bool(true)
+        );
+        let errors = validate(r#"{"age": -1}"#);
+        let errors = errors.as_list().unwrap().elements.borrow();
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .iter()
+            .any(|e| e.as_string().unwrap().starts_with("/age: ")));
+        assert!(errors.iter().any(|e| e
+            .as_string()
+            .unwrap()
+            .contains("\"name\" is a required property")));
+    }
+
+    #[test]
+    fn test_sample_n() {
+        let list = "[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]";
+        set_seed(42);
+        let first = eval(list, "sample_n 4");
+        let second = eval(list, "sample_n 4");
+        assert_eq!(first.to_string(), second.to_string());
+
+        let picked = first.as_list().unwrap().elements.borrow().clone();
+        assert_eq!(picked.len(), 4);
+        for (i, p) in picked.iter().enumerate() {
+            assert!(!picked[i + 1..].contains(p), "{p} was picked twice");
+        }
+
+        assert_eq!(
+            eval(list, "sample_n 10")
+                .as_list()
+                .unwrap()
+                .elements
+                .borrow()
+                .len(),
+            10
+        );
+        eval_err("[1]", "sample_n 2");
+    }
+
+    #[test]
+    fn test_sort() {
+        let sorted = eval("[3, 1, 2]", "sort");
+        assert_eq!(sorted.to_string(), "[1, 2, 3]");
+        assert!(sorted.as_list().unwrap().rest.borrow().is_none());
+
+        let records = r#"[{"name": "b", "n": 2}, {"name": "c", "n": 1}, {"name": "a", "n": 2}]"#;
+        let by_n = eval(records, r#"sort % |r| get r "n""#);
+        assert_eq!(
+            by_n.to_string(),
+            r#"[{name: "c", n: 1}, {name: "b", n: 2}, {name: "a", n: 2}]"#
+        );
+        let by_name = eval(records, r#"sort % |r| get r "name""#);
+        assert_eq!(
+            by_name.to_string(),
+            r#"[{name: "a", n: 2}, {name: "b", n: 2}, {name: "c", n: 1}]"#
+        );
+
+        assert!(matches!(
+            eval_err(r#"[1, "a"]"#, "sort"),
+            error::Error::InvalidType(_)
+        ));
+    }
+
+    #[test]
+    fn test_len() {
+        assert_eq!(&*eval("[1, 2, 3]", "len"), &Value::Int(3));
+        assert_eq!(&*eval("[]", "len"), &Value::Int(0));
+        assert_eq!(&*eval(r#"{"a": 1, "b": 2}"#, "len"), &Value::Int(2));
+        assert_eq!(&*eval("{}", "len"), &Value::Int(0));
+        assert_eq!(&*eval(r#""héllo""#, "len"), &Value::Int(5));
+        assert_eq!(&*eval(r#""""#, "len"), &Value::Int(0));
+        assert_eq!(
+            &*eval("[1, 2, 3]", "len (filter |x| x > 1)"),
+            &Value::Int(2)
+        );
+
+        assert!(matches!(
+            eval_err("1", "len"),
+            error::Error::InvalidTypes(_)
+        ));
+    }
+
+    #[test]
+    fn test_enumerate() {
+        let pairs = eval(r#"["a", "b"]"#, "enumerate");
+        pairs.realize().unwrap();
+        assert_eq!(pairs.to_string(), r#"[[0, "a"], [1, "b"]]"#);
+        let second = pairs.as_list().unwrap().get(1).unwrap().unwrap();
+        assert_eq!(
+            &*second.as_list().unwrap().get(0).unwrap().unwrap(),
+            &Value::Int(1)
+        );
+
+        let taken = eval("null", "take (enumerate (range 9223372036854775807)) 2");
+        taken.realize().unwrap();
+        assert_eq!(taken.to_string(), "[[0, 0], [1, 1]]");
+
+        eval_err(r#"{"a": 1}"#, "enumerate");
+    }
+
+    #[test]
+    fn test_unique() {
+        let value = eval("[1, 2, 2, 3, 1]", "unique");
+        assert_eq!(value.to_string(), "[1, 2, 3]");
+
+        let records = eval(
+            r#"[{"a": 1, "b": [2]}, {"b": [2], "a": 1}, {"a": 2}, "x", "x"]"#,
+            "unique",
+        );
+        assert_eq!(records.to_string(), r#"[{a: 1, b: [2]}, {a: 2}, "x"]"#);
+
+        // Repeats are whatever `==` says is equal
+        assert_eq!(
+            eval("[1, 1.0, [1], [1.0]]", "unique").to_string(),
+            "[1, [1]]"
+        );
+        let numbers = eval("[1, 1.0, 2.0, 2]", "unique");
+        let numbers = numbers.as_list().unwrap().elements.borrow();
+        assert_eq!(
+            numbers.iter().map(|n| &**n).collect::<Vec<_>>(),
+            vec![&Value::Int(1), &Value::Float(2.0)]
+        );
+        let dates = eval(
+            r#""2024-03-01T00:00:00+00:00""#,
+            r#"unique [%, parse_date % "%+", %]"#,
+        );
+        assert_eq!(dates.as_list().unwrap().elements.borrow().len(), 2);
+
+        let functions = eval("null", "unique [1, |x| x, 1]");
+        assert_eq!(functions.as_list().unwrap().elements.borrow().len(), 2);
+
+        eval_err("1", "unique");
+    }
+
+    #[test]
+    fn test_zip() {
+        let same = eval("[1, 2]", r#"zip % ["a", "b"]"#);
+        same.realize().unwrap();
+        assert_eq!(same.to_string(), r#"[[1, "a"], [2, "b"]]"#);
+
+        let shorter = eval("[1, 2, 3]", r#"zip ["a"] %"#);
+        shorter.realize().unwrap();
+        assert_eq!(shorter.to_string(), r#"[["a", 1]]"#);
+
+        let infinite = eval(
+            "null",
+            "take (zip (range 9223372036854775807) (range 5 9223372036854775807)) 3",
+        );
+        infinite.realize().unwrap();
+        assert_eq!(infinite.to_string(), "[[0, 5], [1, 6], [2, 7]]");
+
+        eval_err("[1]", "zip % 1");
+    }
+
+    #[test]
+    fn test_keys_values() {
+        let keys = eval(r#"{"a": 1, "b": 2}"#, "keys");
+        keys.realize().unwrap();
+        assert_eq!(keys.to_string(), r#"["a", "b"]"#);
+        let values = eval(r#"{"a": 1, "b": 2}"#, "values");
+        values.realize().unwrap();
+        assert_eq!(values.to_string(), "[1, 2]");
+
+        // Only the entries that are asked for get realized
+        let endless = SValue::new(Value::Dict(crate::data::Dict {
+            elements: IndexMap::new().into(),
+            rest: Some(Box::new(
+                (0..).map(|i: i64| Ok((format!("k{i}"), SValue::new(Value::Int(i))))),
+            ) as BoxedRest<_>)
+            .into(),
+        }));
+        let keys = super::keys(vec![endless]).unwrap();
+        keys.as_list().unwrap().realize_n(3).unwrap();
+        assert_eq!(keys.sampled(3).to_string(), r#"["k0", "k1", "k2", ...]"#);
+
+        let entries = eval(r#"{"a": 1, "b": 2}"#, "entries");
+        entries.realize().unwrap();
+        assert_eq!(entries.to_string(), r#"[["a", 1], ["b", 2]]"#);
+
+        // Lists are keyed by index
+        let keys = eval("[10, 20]", "keys");
+        keys.realize().unwrap();
+        assert_eq!(keys.to_string(), "[0, 1]");
+        let values = eval("[10, 20]", "values");
+        values.realize().unwrap();
+        assert_eq!(values.to_string(), "[10, 20]");
+        let entries = eval("[10, 20]", "entries (cycle %)");
+        entries.as_list().unwrap().realize_n(3).unwrap();
+        assert_eq!(
+            entries.sampled(3).to_string(),
+            "[[0, 10], [1, 20], [2, 10], ...]"
+        );
+
+        eval_err(r#""text""#, "keys");
+        eval_err(r#""text""#, "entries");
+    }
+
+    #[test]
+    fn test_schema() {
+        let value = eval(r#"[1, 2.5, 3]"#, "%");
+        assert_eq!(schema(&value, true), "[number]");
+        assert_eq!(schema(&value, false), "[int|float]");
+
+        let value = eval(
+            r#"{"id": 1, "tags": ["a", null], "nested": {"ok": true}, "empty": []}"#,
+            "%",
+        );
+        assert_eq!(
+            schema(&value, true),
+            "{id: number, tags: [string|null], nested: {ok: bool}, empty: []}"
+        );
+    }
+
+    #[test]
+    fn test_reduce() {
+        assert_eq!(
+            &*eval("[1, 2, 3.5]", "reduce 0 |acc, x| acc + x"),
+            &Value::Float(6.5)
+        );
+        assert_eq!(
+            &*eval(r#"["a", "b", "c"]"#, r#"reduce "" |acc, x| acc + x"#),
+            &Value::String("abc".into())
+        );
+        assert_eq!(
+            &*eval(r#"{"a": 1, "b": 2}"#, "reduce 10 |acc, x| acc + x"),
+            &Value::Int(13)
+        );
+        assert_eq!(&*eval("[]", "reduce null |acc, x| x"), &Value::Null);
+
+        let mut interpreter = Interpreter::new("[1]".into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        assert!(interpreter
+            .run(command("reduce (cycle %) 0 |acc, x| acc + x").unwrap())
+            .is_err());
+
+        interpreter.set_timeout(Some(50));
+        assert!(matches!(
+            interpreter
+                .run(command("reduce (filter (cycle %) |x| true) 0 |acc, x| acc + x").unwrap()),
+            Err(error::Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_filter() {
+        let evens = eval("[1, 2, 3, 4]", "filter |x| x == 2 or x == 4");
+        evens.realize().unwrap();
+        assert_eq!(evens.to_string(), "[2, 4]");
+
+        // Only as much of the source is pulled as the matches asked for need
+        let evens = eval("[1, 2, 3, 4]", "filter (cycle %) |x| x == 2 or x == 4");
+        evens.as_list().unwrap().realize_n(3).unwrap();
+        assert_eq!(evens.sampled(3).to_string(), "[2, 4, 2, ...]");
+
+        let mut interpreter = Interpreter::new("[1]".into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter.run(command("filter |x| x").unwrap()).unwrap();
+        assert!(matches!(
+            interpreter.value().realize(),
+            Err(error::Error::InvalidType("boolean"))
+        ));
+    }
+
+    #[test]
+    fn test_distinct_count() {
+        let records = r#"[
+            {"city": "Oslo", "tags": ["a"]},
+            {"city": "Lima", "tags": ["b"]},
+            {"city": "Oslo", "tags": ["a"]},
+            {"tags": []}
+        ]"#;
+        assert_eq!(&*eval(records, r#"distinct_count "city""#), &Value::Int(3));
+        assert_eq!(
+            &*eval(records, r#"distinct_count |r| get r "tags""#),
+            &Value::Int(3)
+        );
+        assert_eq!(&*eval("[]", r#"distinct_count % "city""#), &Value::Int(0));
+    }
+
+    #[test]
+    fn test_case_and_trim() {
+        assert_eq!(
+            &*eval(r#""straße""#, "upper"),
+            &Value::String("STRASSE".into())
+        );
+        assert_eq!(
+            &*eval(r#""ÉCOLE Ab""#, "lower"),
+            &Value::String("école ab".into())
+        );
+        assert_eq!(
+            &*eval(r#"" \t a b\n""#, "trim"),
+            &Value::String("a b".into())
+        );
+
+        for f in ["upper", "lower", "trim"] {
+            assert!(matches!(
+                eval_err("[1]", f),
+                error::Error::InvalidType("string")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_replace() {
+        assert_eq!(
+            &*eval(r#""a-b-c""#, r#"replace % "-" ", ""#),
+            &Value::String("a, b, c".into())
+        );
+        assert_eq!(
+            &*eval(r#""aaa""#, r#"replace % "aa" "b""#),
+            &Value::String("ba".into())
+        );
+        assert_eq!(
+            &*eval(r#""abc""#, r#"replace % "x" "y""#),
+            &Value::String("abc".into())
+        );
+
+        eval_err(r#""abc""#, r#"replace % "" "x""#);
+        eval_err(r#""abc""#, r#"replace % 1 "x""#);
+    }
+
+    #[test]
+    fn test_json_escape() {
+        let raw = "say \"hi\"\n\tand \\ bye \u{1}";
+        let escaped = json_escape(vec![SValue::new(Value::String(raw.into()))]).unwrap();
+        assert_eq!(
+            &*escaped,
+            &Value::String(r#"say \"hi\"\n\tand \\ bye \u0001"#.into())
+        );
+        assert_eq!(
+            &*json_unescape(vec![escaped]).unwrap(),
+            &Value::String(raw.into())
+        );
+
+        assert_eq!(
+            &*eval(r#""\\u00e9\\/""#, "json_unescape"),
+            &Value::String("é/".into())
+        );
+        for invalid in [r#"\q"#, r#"\u12"#, r#"a"b"#] {
+            let invalid = SValue::new(Value::String(invalid.into()));
+            assert!(json_unescape(vec![invalid]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_url_encoding() {
+        let text = r#""a b&c=d/é""#;
+        let encoded = eval(text, "url_encode");
+        assert_eq!(&*encoded, &Value::String("a%20b%26c%3Dd%2F%C3%A9".into()));
+        assert_eq!(
+            &*eval(text, "url_decode (url_encode %)"),
+            &Value::String("a b&c=d/é".into())
+        );
+
+        for invalid in [r#""%zz""#, r#""%+1""#, r#""%-1""#, r#""%4""#] {
+            eval_err(invalid, "url_decode");
+        }
+
+        let query = r#""?q=hello+world&lang=en&empty""#;
+        assert_eq!(
+            eval(query, "query_to_dict").to_string(),
+            r#"{q: "hello world", lang: "en", empty: ""}"#
+        );
+        assert_eq!(
+            &*eval(query, "dict_to_query (query_to_dict %)"),
+            &Value::String("q=hello%20world&lang=en&empty=".into())
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        // sha256 of `{"a":1,"b":[true,null]}`
+        let expected = Value::String(
+            "1cc69c7fa23616ca2ec3ee70d24390a6225c8832db8a4c814c7e0e7f942f8668".into(),
+        );
+        let hashed = eval(r#"{"b": [true, null], "a": 1}"#, r#"hash % "sha256""#);
+        assert_eq!(&*hashed, &expected);
+        let reordered = eval(r#"{"a": 1, "b": [true, null]}"#, r#"hash % "sha256""#);
+        assert_eq!(hashed, reordered);
+
+        assert_eq!(
+            &*eval(r#""""#, r#"hash % "md5""#),
+            &Value::String("9d4568c009d203ab10e33ea9953a0264".into())
+        );
+
+        eval_err("1", r#"hash % "crc""#);
+    }
+
+    #[test]
+    fn test_filter_dict() {
+        let is_number = SValue::new(Value::Function(Function {
+            name: "is_number".to_string(),
+            arities: vec![2],
+            implementation: Box::new(|args| {
+                Ok(SValue::new(Value::Bool(args[1].as_number().is_some())))
+            }),
+        }));
+        let dict = eval(r#"{"a": 1, "b": "two", "c": 3.5, "d": null}"#, "%");
+        let filtered = filter_dict(vec![dict, is_number]).unwrap();
+        filtered.realize().unwrap();
+        assert_eq!(filtered.to_string(), "{a: 1, c: 3.5}");
+
+        let flags = r#"{"x": {"keep": true}, "y": {"keep": false}, "z": {"keep": 1}}"#;
+        let value = eval(flags, r#"filter_dict % |k, v| get v "keep""#);
+        assert!(value.realize().is_err());
+        let value = eval(flags, r#"filter_dict % |k, v| get v "keep""#);
+        assert_eq!(
+            value.as_dict().unwrap().get_first().unwrap().unwrap().0,
+            "x"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let record = r#"{"name": "Ann", "age": 31, "tags": ["a"]}"#;
+        assert_eq!(
+            &*eval(record, r#"render "Hi {name}, you are {age}" %"#),
+            &Value::String("Hi Ann, you are 31".into())
+        );
+        assert_eq!(
+            &*eval(record, r#"render "{{{name}}} {tags}}}" %"#),
+            &Value::String(r#"{Ann} ["a"]}"#.into())
+        );
+
+        for template in [r#""{missing}""#, r#""{name""#, r#""name}""#] {
+            eval_err(record, &format!("render {template} %"));
+        }
+    }
+
+    #[test]
+    fn test_first_last_where() {
+        assert_eq!(
+            &*eval("[1, 2, 3]", "first_where (cycle %) |x| x > 1"),
+            &Value::Int(2)
+        );
+        assert_eq!(
+            &*eval("[1, 5, 2, 7, 3]", "last_where % |x| x > 4"),
+            &Value::Int(7)
+        );
+        assert_eq!(&*eval("[1, 2]", "first_where % |x| x > 4"), &Value::Null);
+        assert_eq!(&*eval("[1, 2]", "last_where % |x| x > 4"), &Value::Null);
+    }
+
+    #[cfg(feature = "threadsafe")]
+    #[test]
+    fn test_pmap() {
+        let input = format!("{:?}", (0..200).collect::<Vec<_>>());
+        let cubic = "|n| n * n * n + n * n + 1";
+        let mapped = eval(&input, &format!("map % {cubic}"));
+        mapped.realize().unwrap();
+        assert_eq!(eval(&input, &format!("pmap % {cubic}")), mapped);
+
+        let mut interpreter = Interpreter::new("[1, \"a\"]".into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        assert!(interpreter
+            .run(command("pmap % |x| x * 2").unwrap())
+            .is_err());
+
+        interpreter.set_timeout(Some(50));
+        assert!(matches!(
+            interpreter.run(command("pmap [1, 2] |x| len (cycle [x])").unwrap()),
+            Err(error::Error::Timeout)
+        ));
+    }
+
+    #[cfg(feature = "threadsafe")]
+    #[test]
+    fn test_send_across_threads() {
+        let value = eval(r#"{"a": [1, 2, 3]}"#, r#"map (get % "a") |x| x + 1"#);
+        let realized = std::thread::spawn(move || {
+            value.realize().unwrap();
+            value.to_string()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(realized, "[2, 3, 4]");
+    }
+}
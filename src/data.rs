@@ -1,17 +1,148 @@
 use indexmap::IndexMap;
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
 
 use crate::error;
 
+thread_local! {
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Run `f`, making lazy realization inside it fail with [`error::Error::Timeout`] once the
+/// timeout has passed. Values aren't `Send`, so instead of running `f` on a thread that can be
+/// abandoned, realization checks the deadline before pulling each element.
+pub fn with_deadline<T>(timeout: Option<Duration>, f: impl FnOnce() -> T) -> T {
+    let previous = DEADLINE.get();
+    let deadline = match (previous, timeout.map(|t| Instant::now() + t)) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    DEADLINE.set(deadline);
+    let result = f();
+    DEADLINE.set(previous);
+    result
+}
+
+/// The deadline of the innermost [`with_deadline`] on this thread, to carry it over to others
+pub fn deadline() -> Option<Instant> {
+    DEADLINE.get()
+}
+
+/// Most elements `realize_all` reserves up front. Growing past it is cheap, while a huge lower
+/// bound like the one of `range 10000000000` shouldn't be allocated before the deadline can stop it
+const MAX_RESERVE: usize = 1 << 16;
+
+/// Set by Ctrl-C at the prompt, see [`interrupt`]
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Make the realization that's running fail with [`error::Error::Interrupted`]. It's checked
+/// along with the deadline, so it stops the same things a timeout does.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Forget an [`interrupt`] that came while nothing was running
+pub fn clear_interrupt() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+fn check_deadline() -> error::Result<()> {
+    if INTERRUPTED.swap(false, Ordering::SeqCst) {
+        return Err(error::Error::Interrupted);
+    }
+    match DEADLINE.get() {
+        Some(deadline) if Instant::now() >= deadline => Err(error::Error::Timeout),
+        _ => Ok(()),
+    }
+}
+
+/// Reference counted pointer that values are shared with. `Arc` with the `threadsafe` feature.
+#[cfg(not(feature = "threadsafe"))]
+pub use std::rc::Rc as Shared;
+#[cfg(feature = "threadsafe")]
+pub use std::sync::Arc as Shared;
+
+/// Interior mutability for the lazy parts of values. A `RefCell`-like wrapper around `RwLock`
+/// with the `threadsafe` feature.
+#[cfg(not(feature = "threadsafe"))]
+pub use std::cell::RefCell as Lock;
+#[cfg(feature = "threadsafe")]
+pub use sync::Lock;
+
+/// `Send + Sync` with the `threadsafe` feature, nothing otherwise. For trait objects inside values.
+#[cfg(not(feature = "threadsafe"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "threadsafe"))]
+impl<T: ?Sized> MaybeSend for T {}
+#[cfg(feature = "threadsafe")]
+pub trait MaybeSend: Send + Sync {}
+#[cfg(feature = "threadsafe")]
+impl<T: ?Sized + Send + Sync> MaybeSend for T {}
+
+#[cfg(feature = "threadsafe")]
+mod sync {
+    use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+    #[derive(Debug, Default)]
+    pub struct Lock<T: ?Sized>(RwLock<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(value: T) -> Self {
+            Lock(RwLock::new(value))
+        }
+
+        pub fn take(&self) -> T
+        where
+            T: Default,
+        {
+            std::mem::take(&mut *self.borrow_mut())
+        }
+    }
+
+    impl<T: ?Sized> Lock<T> {
+        pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+            self.0.read().unwrap()
+        }
+
+        pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap()
+        }
+
+        pub fn try_borrow(&self) -> Result<RwLockReadGuard<'_, T>, ()> {
+            self.0.try_read().map_err(|_| ())
+        }
+    }
+
+    impl<T> From<T> for Lock<T> {
+        fn from(value: T) -> Self {
+            Lock::new(value)
+        }
+    }
+
+    impl<T: PartialEq> PartialEq for Lock<T> {
+        fn eq(&self, other: &Self) -> bool {
+            std::ptr::eq(self, other) || *self.borrow() == *other.borrow()
+        }
+    }
+}
+
 /// Shared value
-pub type SValue = Rc<Value>;
+pub type SValue = Shared<Value>;
 
 #[derive(Debug, PartialEq)]
 pub enum Value {
     Null,
     Bool(bool),
-    Int(u64),
+    Int(i64),
+    /// An integer outside of `Int`'s range, as its decimal digits. Arithmetic treats it as an
+    /// (approximate) float, while JSON output keeps it exact.
+    BigInt(String),
     Float(f64),
+    /// A point in time, written as an ISO-8601 string in JSON
+    Date(chrono::DateTime<chrono::FixedOffset>),
     // TODO: strings can be lazy?
     String(String),
     List(List),
@@ -19,43 +150,77 @@ pub enum Value {
     Function(Function),
 }
 
-type LazyRest<T> = RefCell<Option<Box<dyn Iterator<Item = error::Result<T>>>>>;
+/// The not yet realized elements of a container
+pub trait Rest<T>: Iterator<Item = error::Result<T>> + MaybeSend {}
+impl<T, I: Iterator<Item = error::Result<T>> + MaybeSend> Rest<T> for I {}
+
+pub type BoxedRest<T> = Box<dyn Rest<T>>;
+
+/// The next element of `rest`. An element that fails is put back in front of the rest, so
+/// realizing it again fails the same way instead of the element going missing.
+fn pull<T: MaybeSend + 'static>(rest: &mut BoxedRest<T>) -> Option<error::Result<T>> {
+    let next = rest.next();
+    if let Some(Err(err)) = &next {
+        let err = err.clone();
+        replace_with::replace_with_or_abort(rest, |rest| {
+            Box::new(std::iter::once(Err(err)).chain(rest))
+        });
+    }
+    next
+}
+
+type LazyRest<T> = Lock<Option<BoxedRest<T>>>;
 
 /// Lazily evaluated list
 pub struct List {
-    pub elements: RefCell<Vec<SValue>>,
+    pub elements: Lock<Vec<SValue>>,
     pub rest: LazyRest<SValue>,
 }
 
 /// Lazily evaluated dict
+///
+/// Entries keep the order they were realized in. Builtins that produce a dict from another one
+/// keep that order too: a key that gets a new value stays where it was, and new keys are appended
+/// at the end. Only `sorted` reorders the keys.
 pub struct Dict {
-    pub elements: RefCell<IndexMap<String, SValue>>,
+    pub elements: Lock<IndexMap<String, SValue>>,
     pub rest: LazyRest<(String, SValue)>,
 }
 
+pub trait Implementation: Fn(Vec<SValue>) -> error::Result<SValue> + MaybeSend {}
+impl<F: Fn(Vec<SValue>) -> error::Result<SValue> + MaybeSend> Implementation for F {}
+
 pub struct Function {
     pub name: String,
     pub arities: Vec<usize>,
-    pub implementation: Box<dyn Fn(Vec<SValue>) -> error::Result<SValue>>,
+    pub implementation: Box<dyn Implementation>,
 }
 
 // Impls
 
+/// How many elements of each container are realized for a preview by default
+pub const DEFAULT_SAMPLE_WIDTH: usize = 3;
+
 impl Value {
     /// Realize the inner value enough for a user to have a good ol' look at it
     pub fn sample(&self) -> error::Result<()> {
-        // TODO: replace simple "3" heuristic with something better. maybe recursive "size/complexity estimation"
+        self.sample_n(DEFAULT_SAMPLE_WIDTH)
+    }
+
+    /// Realize the first `width` elements of each container, recursively
+    pub fn sample_n(&self, width: usize) -> error::Result<()> {
+        // TODO: replace simple width heuristic with something better. maybe recursive "size/complexity estimation"
         match self {
             Value::List(l) => {
-                l.realize_n(3)?;
-                for e in l.elements.borrow().iter() {
-                    e.sample()?;
+                l.realize_n(width)?;
+                for e in l.elements.borrow().iter().take(width) {
+                    e.sample_n(width)?;
                 }
             }
             Value::Dict(m) => {
-                m.realize_n(3)?;
-                for e in m.elements.borrow().values() {
-                    e.sample()?;
+                m.realize_n(width)?;
+                for e in m.elements.borrow().values().take(width) {
+                    e.sample_n(width)?;
                 }
             }
             _ => (),
@@ -63,15 +228,97 @@ impl Value {
         Ok(())
     }
 
+    /// Sample and show at most `width` elements of each container, like [`Value::sample_n`] and
+    /// [`Value::sampled`]. Showing a container stops at the first element that fails to realize,
+    /// with an error marker in its place.
+    pub fn render_lenient(&self, width: usize) -> String {
+        match self {
+            Value::List(l) => {
+                let mut shown = vec![];
+                let mut index = 0;
+                while shown.len() < width {
+                    match l.get(index) {
+                        Ok(Some(e)) => {
+                            shown.push(e.render_lenient(width));
+                            index += 1;
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            shown.push(format!("<error: {err}>"));
+                            break;
+                        }
+                    }
+                }
+                if l.elements.borrow().len() > index || l.rest.borrow().is_some() {
+                    shown.push("...".to_string());
+                }
+                format!("[{}]", shown.join(", "))
+            }
+            Value::Dict(m) => {
+                let mut shown = vec![];
+                let mut index = 0;
+                while shown.len() < width {
+                    match m.get_nth(index) {
+                        Ok(Some((k, v))) => {
+                            shown.push(format!("{}: {}", k, v.render_lenient(width)));
+                            index += 1;
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            shown.push(format!("<error: {err}>"));
+                            break;
+                        }
+                    }
+                }
+                if m.elements.borrow().len() > index || m.rest.borrow().is_some() {
+                    shown.push("...".to_string());
+                }
+                format!("{{{}}}", shown.join(", "))
+            }
+            value => value.to_string(),
+        }
+    }
+
+    /// Display showing at most `width` elements of each container, matching [`Value::sample_n`]
+    pub fn sampled(&self, width: usize) -> Sampled<'_> {
+        Sampled { value: self, width }
+    }
+
+    /// Realize the inner value fully, recursively
     pub fn realize(&self) -> error::Result<()> {
         match self {
-            Value::List(l) => l.realize_all()?,
-            Value::Dict(m) => m.realize_all()?,
+            Value::List(l) => {
+                l.realize_all()?;
+                for e in l.elements.borrow().iter() {
+                    e.realize()?;
+                }
+            }
+            Value::Dict(m) => {
+                m.realize_all()?;
+                for e in m.elements.borrow().values() {
+                    e.realize()?;
+                }
+            }
             _ => (),
         }
         Ok(())
     }
 
+    /// Whether nothing inside is left to realize
+    pub fn is_realized(&self) -> bool {
+        match self {
+            Value::List(l) => {
+                matches!(l.rest.try_borrow().as_deref(), Ok(None))
+                    && l.elements.borrow().iter().all(|e| e.is_realized())
+            }
+            Value::Dict(m) => {
+                matches!(m.rest.try_borrow().as_deref(), Ok(None))
+                    && m.elements.borrow().values().all(|e| e.is_realized())
+            }
+            _ => true,
+        }
+    }
+
     pub(crate) fn as_dict(&self) -> Option<&Dict> {
         match self {
             Value::Dict(d) => Some(d),
@@ -89,6 +336,7 @@ impl Value {
     pub(crate) fn as_number(&self) -> Option<f64> {
         match self {
             Value::Int(n) => Some(*n as f64),
+            Value::BigInt(digits) => digits.parse().ok(),
             Value::Float(n) => Some(*n),
             _ => None,
         }
@@ -116,23 +364,38 @@ impl List {
     }
 
     pub fn realize_all(&self) -> error::Result<()> {
-        if let Some(rest) = self.rest.take() {
+        if let Some(mut rest) = self.rest.take() {
             let mut elems = self.elements.borrow_mut();
-            for elem in rest {
-                elems.push(elem?);
+            let _ = elems.try_reserve(rest.size_hint().0.min(MAX_RESERVE));
+            loop {
+                if let Err(err) = check_deadline() {
+                    drop(elems);
+                    *self.rest.borrow_mut() = Some(rest);
+                    return Err(err);
+                }
+                match pull(&mut rest) {
+                    Some(Ok(elem)) => elems.push(elem),
+                    Some(Err(err)) => {
+                        drop(elems);
+                        *self.rest.borrow_mut() = Some(rest);
+                        return Err(err);
+                    }
+                    None => break,
+                }
             }
         }
         Ok(())
     }
 
     /// Expand to length n
-    fn realize_n(&self, n: usize) -> error::Result<()> {
+    pub fn realize_n(&self, n: usize) -> error::Result<()> {
         let mut elements_needed = n.saturating_sub(self.elements.borrow().len());
 
         if let Some(rest) = self.rest.borrow_mut().as_mut() {
             while elements_needed > 0 {
+                check_deadline()?;
                 let mut elems = self.elements.borrow_mut();
-                if let Some(next) = rest.next() {
+                if let Some(next) = pull(rest) {
                     let next = next?;
                     elems.push(next);
                     elements_needed -= 1;
@@ -168,6 +431,27 @@ impl Iterator for ListIter {
         self.index += 1;
         self.list.as_list().unwrap().get(self.index - 1).transpose()
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let list = self.list.as_list().unwrap();
+        // While `realize_all` pushes to the elements, the rest is taken out of the list
+        let Ok(elements) = list.elements.try_borrow() else {
+            return (0, None);
+        };
+        let realized = elements.len().saturating_sub(self.index);
+        match list.rest.try_borrow().as_deref() {
+            Ok(None) => (realized, Some(realized)),
+            Ok(Some(rest)) => {
+                let (lower, upper) = rest.size_hint();
+                (
+                    realized.saturating_add(lower),
+                    upper.and_then(|u| u.checked_add(realized)),
+                )
+            }
+            // The rest is being realized right now
+            Err(_) => (realized, None),
+        }
+    }
 }
 
 impl std::fmt::Display for Value {
@@ -176,7 +460,9 @@ impl std::fmt::Display for Value {
             Value::Null => write!(f, "null"),
             Value::Bool(b) => write!(f, "{}", b),
             Value::Int(n) => write!(f, "{}", n),
+            Value::BigInt(digits) => write!(f, "{}", digits),
             Value::Float(n) => write!(f, "{}", n),
+            Value::Date(d) => write!(f, "{}", d.to_rfc3339()),
             Value::String(s) => write!(f, "{:?}", s), // TODO: hide the rest if its too much
             Value::List(l) => write!(f, "{}", l),
             Value::Dict(m) => write!(f, "{}", m),
@@ -185,6 +471,53 @@ impl std::fmt::Display for Value {
     }
 }
 
+pub struct Sampled<'a> {
+    value: &'a Value,
+    width: usize,
+}
+
+impl std::fmt::Display for Sampled<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.value {
+            Value::List(l) => {
+                let elements = l.elements.borrow();
+                write!(f, "[")?;
+                for (i, e) in elements.iter().take(self.width).enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", e.sampled(self.width))?;
+                }
+                if elements.len() > self.width || l.rest.borrow().is_some() {
+                    if !elements.is_empty() && self.width > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "...")?;
+                }
+                write!(f, "]")
+            }
+            Value::Dict(m) => {
+                let elements = m.elements.borrow();
+                write!(f, "{{")?;
+                for (i, (k, v)) in elements.iter().take(self.width).enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", k, v.sampled(self.width))?;
+                }
+                if elements.len() > self.width || m.rest.borrow().is_some() {
+                    if !elements.is_empty() && self.width > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "...")?;
+                }
+                write!(f, "}}")
+            }
+            value => write!(f, "{}", value),
+        }
+    }
+}
+
 impl std::fmt::Debug for List {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("List")
@@ -227,7 +560,7 @@ impl Dict {
         Ok(self.elements.borrow().get(key).cloned())
     }
 
-    fn get_nth(&self, n: usize) -> error::Result<Option<(String, SValue)>> {
+    pub(crate) fn get_nth(&self, n: usize) -> error::Result<Option<(String, SValue)>> {
         self.realize_n(n + 1)?;
         self.elements
             .borrow()
@@ -249,12 +582,13 @@ impl Dict {
 
     /// Expand to size n
     pub fn realize_n(&self, n: usize) -> error::Result<()> {
-        let mut elements_needed = (n + 1).saturating_sub(self.elements.borrow().len());
+        let mut elements_needed = n.saturating_sub(self.elements.borrow().len());
 
         if let Some(rest) = self.rest.borrow_mut().as_mut() {
             while elements_needed > 0 {
+                check_deadline()?;
                 let mut elems = self.elements.borrow_mut();
-                if let Some(next) = rest.next() {
+                if let Some(next) = pull(rest) {
                     let (k, v) = next?;
                     elems.insert(k, v);
                     elements_needed -= 1;
@@ -271,12 +605,26 @@ impl Dict {
     }
 
     pub fn realize_look_for(&self, key: &str) -> error::Result<Option<SValue>> {
-        if let Some(rest) = self.rest.take() {
+        if let Some(mut rest) = self.rest.take() {
             let mut elems = self.elements.borrow_mut();
-            for elem in rest {
-                let (k, v) = elem?;
+            loop {
+                if let Err(err) = check_deadline() {
+                    drop(elems);
+                    *self.rest.borrow_mut() = Some(rest);
+                    return Err(err);
+                }
+                let (k, v) = match pull(&mut rest) {
+                    Some(Ok(elem)) => elem,
+                    Some(Err(err)) => {
+                        drop(elems);
+                        *self.rest.borrow_mut() = Some(rest);
+                        return Err(err);
+                    }
+                    None => break,
+                };
                 elems.insert(k.clone(), v.clone());
                 if k == key {
+                    *self.rest.borrow_mut() = Some(rest);
                     return Ok(Some(v));
                 }
             }
@@ -285,11 +633,26 @@ impl Dict {
     }
 
     pub fn realize_all(&self) -> error::Result<()> {
-        if let Some(rest) = self.rest.take() {
+        if let Some(mut rest) = self.rest.take() {
             let mut elems = self.elements.borrow_mut();
-            for elem in rest {
-                let (k, v) = elem?;
-                elems.insert(k, v);
+            let _ = elems.try_reserve(rest.size_hint().0.min(MAX_RESERVE));
+            loop {
+                if let Err(err) = check_deadline() {
+                    drop(elems);
+                    *self.rest.borrow_mut() = Some(rest);
+                    return Err(err);
+                }
+                match pull(&mut rest) {
+                    Some(Ok((k, v))) => {
+                        elems.insert(k, v);
+                    }
+                    Some(Err(err)) => {
+                        drop(elems);
+                        *self.rest.borrow_mut() = Some(rest);
+                        return Err(err);
+                    }
+                    None => break,
+                }
             }
         }
         Ok(())
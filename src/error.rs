@@ -1,5 +1,5 @@
 #[allow(clippy::enum_variant_names)]
-#[derive(Debug, thiserror::Error)]
+#[derive(Clone, Debug, thiserror::Error)]
 pub enum Error {
     #[error("Function {0} not found")]
     FunctionNotFound(String),
@@ -9,6 +9,8 @@ pub enum Error {
     BuiltinFunctionError(String),
     #[error("Ran >> on an empty sequence")]
     ShiftRightEmptySequence,
+    #[error("Ran >> with key and value names on a list, only dicts have keys")]
+    ShiftRightListWithKeys,
     #[error("Ran << while not in a shift")]
     ShiftLeftNotInShift,
     #[error("Variable {0} not found")]
@@ -17,5 +19,11 @@ pub enum Error {
     InvalidType(&'static str),
     #[error("Invalid type, expected one of {0:?}")]
     InvalidTypes(&'static [&'static str]),
+    #[error("Checkpoint {0} not found")]
+    CheckpointNotFound(String),
+    #[error("Timed out")]
+    Timeout,
+    #[error("Interrupted")]
+    Interrupted,
 }
 pub type Result<T> = std::result::Result<T, Error>;
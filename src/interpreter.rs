@@ -1,9 +1,13 @@
+use indexmap::IndexMap;
 use std::borrow::Borrow;
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::data::{Dict, Function, List, SValue, Value};
+use crate::data::{
+    with_deadline, BoxedRest, Dict, Function, List, Lock, MaybeSend, SValue, Shared, Value,
+    DEFAULT_SAMPLE_WIDTH,
+};
 use crate::parser::{Command, Expression};
 use crate::{builtin, error};
 
@@ -11,10 +15,73 @@ use crate::{builtin, error};
 pub struct Interpreter {
     settings: Settings,
     program: Program,
+    /// The command history at the time each checkpoint was made
+    checkpoints: HashMap<String, Vec<Command>>,
+    /// Expressions that user bindings were defined with, see [`Interpreter::define`]
+    definitions: HashMap<String, Expression>,
+    /// Values stashed with [`Interpreter::pin`], in the order they were pinned
+    pins: IndexMap<String, SValue>,
 }
 
 #[derive(Debug, Clone)]
-struct Settings {}
+struct Settings {
+    /// Log every evaluated expression, see [`Interpreter::set_trace`]. Shared with the copies of
+    /// the settings that lambdas keep, so turning it on reaches lambdas made before.
+    trace: Shared<Lock<Option<Tracer>>>,
+    /// How many elements of each container the prompt realizes and shows
+    sample_width: usize,
+    error_mode: ErrorMode,
+    /// Show what changed in the value after each command
+    diff_after_command: bool,
+    /// Budget for running a command, see [`crate::data::with_deadline`]
+    timeout_ms: Option<u64>,
+    /// Columns that the prompt's output is cut to
+    terminal_width: usize,
+    /// Show the status and the value on the same line as the prompt
+    compact: bool,
+    /// Results of earlier builtin calls, see [`Interpreter::set_memoize`]
+    memo: Option<Shared<Lock<HashMap<String, SValue>>>>,
+}
+
+/// Width to use when stdout isn't a terminal
+const DEFAULT_TERMINAL_WIDTH: usize = 80;
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            trace: Shared::new(Lock::new(None)),
+            sample_width: DEFAULT_SAMPLE_WIDTH,
+            error_mode: ErrorMode::Lenient,
+            diff_after_command: false,
+            timeout_ms: None,
+            terminal_width: terminal_size::terminal_size()
+                .map(|(terminal_size::Width(w), _)| w as usize)
+                .unwrap_or(DEFAULT_TERMINAL_WIDTH),
+            compact: false,
+            memo: None,
+        }
+    }
+}
+
+/// What showing a value does when some of its elements fail to realize
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorMode {
+    /// Don't show the value at all
+    Strict,
+    /// Show the rest of the value, with a marker in place of the failed elements
+    Lenient,
+}
+
+trait Output: Write + MaybeSend {}
+impl<W: Write + MaybeSend> Output for W {}
+
+/// Writes each evaluated expression in source form and its result, indented by recursion depth.
+/// Results are shown as far as they're realized, tracing doesn't realize more of them.
+#[derive(Clone)]
+struct Tracer {
+    depth: Shared<AtomicUsize>,
+    out: Shared<Lock<dyn Output>>,
+}
 
 #[derive(Debug, Clone)]
 enum Program {
@@ -38,6 +105,15 @@ enum Program {
 struct CachedCommand {
     command: ExecutedCommand,
     result: SValue,
+    /// For a group, the state inside the shift right before the shift-left, so it can be undone
+    inside: Option<Box<Inside>>,
+}
+
+#[derive(Debug, Clone)]
+struct Inside {
+    initial: SValue,
+    scope: Scope,
+    commands: Vec<CachedCommand>,
 }
 
 #[derive(Debug, Clone)]
@@ -55,29 +131,48 @@ enum ExecutedCommand {
 
 #[derive(Debug, Clone)]
 // TODO: scope should include "this", and a command can modify the scope
-pub struct Scope(Rc<HashMap<String, SValue>>);
+pub struct Scope(Shared<HashMap<String, SValue>>);
+
+impl Scope {
+    pub fn get(&self, name: &str) -> Option<&SValue> {
+        self.0.get(name)
+    }
+}
 
 impl Interpreter {
     pub fn new(input: String) -> Self {
         Self {
-            settings: Settings {},
+            settings: Settings::default(),
             program: Program::Closed {
                 initial: SValue::new(Value::String(input)),
-                scope: Scope(Rc::new(builtin::builtin_functions())),
+                scope: Scope(Shared::new(builtin::builtin_functions())),
                 commands: vec![],
             },
+            checkpoints: HashMap::new(),
+            definitions: HashMap::new(),
+            pins: IndexMap::new(),
         }
     }
 
     pub fn run(&mut self, command: Command) -> error::Result<()> {
+        with_deadline(self.timeout(), || self.run_untimed(command))
+    }
+
+    fn run_untimed(&mut self, command: Command) -> error::Result<()> {
         let this = self.value();
         let mut scope = self.scope();
         match command.clone() {
             Command::Expression(expr) => {
-                let result = Interpreter::eval_expression(scope.clone(), expr.clone(), this)?;
+                let result = Interpreter::eval_expression(
+                    &self.settings,
+                    scope.clone(),
+                    expr.clone(),
+                    this,
+                )?;
                 self.program.push(CachedCommand {
                     command: ExecutedCommand::Simple { command },
                     result,
+                    inside: None,
                 });
             }
             Command::ShiftRight(kv) => match (&*this, kv) {
@@ -98,7 +193,7 @@ impl Interpreter {
                     let first = d
                         .get_first()?
                         .ok_or(error::Error::ShiftRightEmptySequence)?;
-                    let scope_inner = Rc::make_mut(&mut scope.0);
+                    let scope_inner = Shared::make_mut(&mut scope.0);
                     scope_inner.insert(kv.0.clone(), SValue::new(Value::String(first.0)));
                     scope_inner.insert(kv.1.clone(), first.1);
                     replace_with::replace_with_or_abort(&mut self.program, |p| Program::Open {
@@ -111,7 +206,8 @@ impl Interpreter {
                         commands: vec![],
                     });
                 }
-                _ => todo!("invalid shift right"),
+                (Value::List(_), Some(_)) => return Err(error::Error::ShiftRightListWithKeys),
+                _ => return Err(error::Error::InvalidTypes(&["list", "dict"])),
             },
             Command::ShiftLeft(leave_kv) => {
                 let Program::Open {
@@ -119,7 +215,7 @@ impl Interpreter {
                     kv: enter_kv,
                     mut history,
                     initial,
-                    scope,
+                    scope: inside_scope,
                     commands,
                 } = self.program.clone()
                 else {
@@ -128,47 +224,73 @@ impl Interpreter {
                 // "this" before that was the preview of the first element,
                 // now we care about the whole container
                 let this = history.value();
-                let mut iterable: Box<dyn Iterator<Item = _>> = match &*this {
-                    Value::List(l) => Box::new(List::into_iter(this.clone())),
-                    Value::Dict(d) => Box::new(Dict::into_iter(this.clone()).map(|r| {
-                        r.map(|(k, v)| {
-                            SValue::new(Value::List(List {
-                                elements: vec![SValue::new(Value::String(k)), v].into(),
-                                rest: None.into(),
-                            }))
-                        })
-                    })),
-                    _ => unreachable!("shifting left when last value is non sequence"),
-                };
+                let scope = inside_scope.clone();
 
-                if let Some((k_var, v_var)) = enter_kv {
-                    todo!()
+                // Run the commands again on each element, in the scope the shift would give it
+                let settings = self.settings.clone();
+                let element_commands = commands.clone();
+                let run_element = move |initial: SValue, scope: Scope| -> error::Result<_> {
+                    let mut interpreter = Interpreter {
+                        settings: settings.clone(),
+                        program: Program::Closed {
+                            initial,
+                            scope,
+                            commands: vec![],
+                        },
+                        checkpoints: HashMap::new(),
+                        definitions: HashMap::new(),
+                        pins: IndexMap::new(),
+                    };
+                    for command in &element_commands {
+                        interpreter.rerun(&command.command)?;
+                    }
+                    Ok(interpreter.value())
+                };
+                let iterable: BoxedRest<SValue> = if let Some((k_var, v_var)) = enter_kv.clone() {
+                    Box::new(Dict::into_iter(this).map(move |e| {
+                        let (k, v) = e?;
+                        let mut scope = scope.clone();
+                        let scope_inner = Shared::make_mut(&mut scope.0);
+                        scope_inner.insert(k_var.clone(), SValue::new(Value::String(k)));
+                        scope_inner.insert(v_var.clone(), v);
+                        run_element(SValue::new(Value::Null), scope)
+                    }))
                 } else {
-                    let commands = commands.clone();
-                    let interpreter = self.clone();
-                    let history = history.clone();
-                    iterable = Box::new(iterable.map(move |e| -> error::Result<_> {
+                    let iterable =
+                        sequence_iter(this).expect("shifting left when last value is non sequence");
+                    Box::new(iterable.map(move |e| run_element(e?, scope.clone())))
+                };
+                let result = if let Some((k_expr, v_expr)) = leave_kv.clone() {
+                    // The pair is evaluated with each result as `%`, in the scope outside the shift
+                    let settings = self.settings.clone();
+                    let scope = history.scope();
+                    let entries = iterable.map(move |e| {
                         let e = e?;
-                        let mut interpreter = Interpreter {
-                            settings: interpreter.settings.clone(),
-                            program: Program::Closed {
-                                initial: e,
-                                scope: scope.clone(),
-                                commands: vec![],
-                            },
+                        let k = Interpreter::eval_expression(
+                            &settings,
+                            scope.clone(),
+                            k_expr.clone(),
+                            e.clone(),
+                        )?;
+                        let Value::String(k) = &*k else {
+                            return Err(error::Error::InvalidType("string"));
                         };
-                        for command in &commands {
-                            interpreter.rerun(&command.command)?;
-                        }
-                        Ok(interpreter.value())
-                    }));
-                }
-                let result = if let Some((k_var, v_var)) = leave_kv {
-                    todo!()
+                        let v = Interpreter::eval_expression(
+                            &settings,
+                            scope.clone(),
+                            v_expr.clone(),
+                            e,
+                        )?;
+                        Ok((k.clone(), v))
+                    });
+                    SValue::new(Value::Dict(Dict {
+                        elements: Lock::new(IndexMap::new()),
+                        rest: Lock::new(Some(Box::new(entries))),
+                    }))
                 } else {
                     SValue::new(Value::List(List {
-                        elements: RefCell::new(vec![]),
-                        rest: RefCell::new(Some(iterable)),
+                        elements: Lock::new(vec![]),
+                        rest: Lock::new(Some(iterable)),
                     }))
                 };
                 history.push(CachedCommand {
@@ -179,6 +301,11 @@ impl Interpreter {
                         leave_kv,
                     },
                     result,
+                    inside: Some(Box::new(Inside {
+                        initial,
+                        scope: inside_scope,
+                        commands,
+                    })),
                 });
                 replace_with::replace_with_or_abort(&mut self.program, |p| *history);
             }
@@ -204,6 +331,178 @@ impl Interpreter {
         }
     }
 
+    /// Reuse the results of builtin calls with the same arguments. Only calls whose arguments are
+    /// fully realized and hold no functions are cached, and turning it off forgets the results.
+    pub fn set_memoize(&mut self, enabled: bool) {
+        if !enabled {
+            self.settings.memo = None;
+        } else if self.settings.memo.is_none() {
+            self.settings.memo = Some(Shared::new(Lock::new(HashMap::new())));
+        }
+    }
+
+    pub fn set_trace(&mut self, enabled: bool) {
+        *self.settings.trace.borrow_mut() = enabled.then(|| Tracer {
+            depth: Shared::new(AtomicUsize::new(0)),
+            out: Shared::new(Lock::new(std::io::stderr())),
+        });
+    }
+
+    /// Evaluate the expression against the current value and bind the result in the current scope
+    pub fn define(&mut self, name: String, expr: Expression) -> error::Result<()> {
+        let value =
+            Interpreter::eval_expression(&self.settings, self.scope(), expr.clone(), self.value())?;
+        Shared::make_mut(&mut self.program.scope_mut().0).insert(name.clone(), value);
+        self.definitions.insert(name, expr);
+        Ok(())
+    }
+
+    /// Drop every user binding, leaving only the builtins and the variables bound by shifts
+    pub fn reset_scope(&mut self) {
+        self.program.reset_scope();
+        self.definitions.clear();
+    }
+
+    pub fn definition(&self, name: &str) -> Option<&Expression> {
+        self.definitions.get(name)
+    }
+
+    pub fn checkpoint(&mut self, name: String) {
+        self.checkpoints.insert(name, self.program.history());
+    }
+
+    /// Replay the commands that led to the checkpoint, from the start. Commands undone since
+    /// then are still replayed, so it can go back to a branch that was left.
+    pub fn restore(&mut self, name: &str) -> error::Result<()> {
+        let history = self
+            .checkpoints
+            .get(name)
+            .ok_or_else(|| error::Error::CheckpointNotFound(name.to_string()))?
+            .clone();
+
+        let (initial, _) = self.root();
+        *self = self.replay(initial, &history)?;
+        Ok(())
+    }
+
+    /// Stash the current value under a name
+    pub fn pin(&mut self, name: String) {
+        self.pins.insert(name, self.value());
+    }
+
+    pub fn pins(&self) -> &IndexMap<String, SValue> {
+        &self.pins
+    }
+
+    /// A new session starting from the value, with the same settings and bindings
+    pub fn fork(&self, initial: SValue) -> Interpreter {
+        self.replay(initial, &[])
+            .expect("replaying no commands can't fail")
+    }
+
+    /// Run the whole history again, starting from a different initial value
+    pub fn replay_on(&self, initial: SValue) -> error::Result<Interpreter> {
+        self.replay(initial, &self.program.history())
+    }
+
+    fn replay(&self, initial: SValue, history: &[Command]) -> error::Result<Interpreter> {
+        let (_, scope) = self.root();
+        let mut interpreter = Interpreter {
+            settings: self.settings.clone(),
+            program: Program::Closed {
+                initial,
+                scope,
+                commands: vec![],
+            },
+            checkpoints: self.checkpoints.clone(),
+            definitions: self.definitions.clone(),
+            pins: self.pins.clone(),
+        };
+        for command in history {
+            interpreter.rerun(&ExecutedCommand::Simple {
+                command: command.clone(),
+            })?;
+        }
+        Ok(interpreter)
+    }
+
+    /// Initial value and scope of the outermost program
+    fn root(&self) -> (SValue, Scope) {
+        let mut root = &self.program;
+        while let Program::Open { history, .. } = root {
+            root = history;
+        }
+        let Program::Closed { initial, scope, .. } = root else {
+            unreachable!("root program is always closed")
+        };
+        (initial.clone(), scope.clone())
+    }
+
+    /// Evaluate the expression against the current value, without adding it to the history
+    pub fn evaluate(&self, expr: Expression) -> error::Result<SValue> {
+        Interpreter::eval_expression(&self.settings, self.scope(), expr, self.value())
+    }
+
+    /// Realize the first `n` elements of the current list or dict, leaving the rest lazy
+    pub fn realize_to(&self, n: usize) -> error::Result<()> {
+        let value = self.value();
+        with_deadline(self.timeout(), || match &*value {
+            Value::List(l) => l.realize_n(n),
+            Value::Dict(d) => d.realize_n(n),
+            _ => Err(error::Error::InvalidTypes(&["list", "dict"])),
+        })
+    }
+
+    pub fn sample_width(&self) -> usize {
+        self.settings.sample_width
+    }
+
+    pub fn set_sample_width(&mut self, width: usize) {
+        self.settings.sample_width = width;
+    }
+
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        self.settings
+            .timeout_ms
+            .map(std::time::Duration::from_millis)
+    }
+
+    pub fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.settings.timeout_ms = timeout_ms;
+    }
+
+    pub fn terminal_width(&self) -> usize {
+        self.settings.terminal_width
+    }
+
+    pub fn set_terminal_width(&mut self, width: usize) {
+        self.settings.terminal_width = width;
+    }
+
+    pub fn compact(&self) -> bool {
+        self.settings.compact
+    }
+
+    pub fn set_compact(&mut self, enabled: bool) {
+        self.settings.compact = enabled;
+    }
+
+    pub fn diff_mode(&self) -> bool {
+        self.settings.diff_after_command
+    }
+
+    pub fn set_diff_mode(&mut self, enabled: bool) {
+        self.settings.diff_after_command = enabled;
+    }
+
+    pub fn error_mode(&self) -> ErrorMode {
+        self.settings.error_mode
+    }
+
+    pub fn set_error_mode(&mut self, mode: ErrorMode) {
+        self.settings.error_mode = mode;
+    }
+
     pub fn undo(&mut self) {
         self.program.pop();
     }
@@ -220,80 +519,195 @@ impl Interpreter {
         self.program.status()
     }
 
-    fn eval_expression(scope: Scope, e: Expression, this: SValue) -> error::Result<SValue> {
+    /// The whole session as one command per line, indented inside shifts
+    pub fn outline(&self) -> Vec<String> {
+        self.program.outline().0
+    }
+
+    fn eval_expression(
+        settings: &Settings,
+        scope: Scope,
+        e: Expression,
+        this: SValue,
+    ) -> error::Result<SValue> {
+        let tracer = Lock::borrow(&settings.trace).clone();
+        let Some(tracer) = tracer else {
+            return Interpreter::eval_expression_untraced(settings, scope, e, this);
+        };
+        tracer.enter(&e);
+        let result = Interpreter::eval_expression_untraced(settings, scope, e, this);
+        tracer.leave(&result);
+        result
+    }
+
+    fn eval_expression_untraced(
+        settings: &Settings,
+        scope: Scope,
+        e: Expression,
+        this: SValue,
+    ) -> error::Result<SValue> {
         Ok(match e {
             Expression::This => this.clone(),
             Expression::Literal(l) => l,
 
             Expression::Plus(x, y) => {
-                match eval_number_pair(this.clone(), scope.clone(), *x.clone(), *y.clone()) {
-                    Ok((x, y)) => SValue::new(Value::Float(x + y)),
-                    Err(_) => {
-                        let x = Interpreter::eval_expression(scope.clone(), *x, this.clone())?;
-                        let x = x
-                            .as_string()
-                            .ok_or(error::Error::InvalidTypes(&["string", "number"]))?;
-                        let y = Interpreter::eval_expression(scope.clone(), *y, this.clone())?;
-                        let y = y
-                            .as_string()
-                            .ok_or(error::Error::InvalidTypes(&["string", "number"]))?;
+                let x = Interpreter::eval_expression(settings, scope.clone(), *x, this.clone())?;
+                let y = Interpreter::eval_expression(settings, scope.clone(), *y, this.clone())?;
+                match (&*x, &*y) {
+                    (Value::String(x), Value::String(y)) => {
                         SValue::new(Value::String(format!("{}{}", x, y)))
                     }
+                    (Value::List(_), Value::List(_)) => SValue::new(Value::List(List {
+                        elements: Lock::new(vec![]),
+                        rest: Lock::new(Some(Box::new(
+                            List::into_iter(x.clone()).chain(List::into_iter(y.clone())),
+                        ))),
+                    })),
+                    (Value::Dict(x), Value::Dict(y)) => {
+                        x.realize_all()?;
+                        y.realize_all()?;
+                        // Keys already in `x` keep their position, the value from `y` wins
+                        let mut elements = x.elements.borrow().clone();
+                        for (k, v) in y.elements.borrow().iter() {
+                            elements.insert(k.clone(), v.clone());
+                        }
+                        SValue::new(Value::Dict(Dict {
+                            elements: elements.into(),
+                            rest: None.into(),
+                        }))
+                    }
+                    _ => match arithmetic(&x, &y, i64::checked_add, |x, y| x + y) {
+                        Some(sum) => sum,
+                        None => {
+                            return Err(error::Error::InvalidTypes(&[
+                                "number", "string", "list", "dict",
+                            ]))
+                        }
+                    },
                 }
             }
             Expression::Minus(x, y) => {
-                let (x, y) = eval_number_pair(this.clone(), scope.clone(), *x, *y)?;
-                SValue::new(Value::Float(x - y))
+                let x = Interpreter::eval_expression(settings, scope.clone(), *x, this.clone())?;
+                let y = Interpreter::eval_expression(settings, scope.clone(), *y, this.clone())?;
+                arithmetic(&x, &y, i64::checked_sub, |x, y| x - y)
+                    .ok_or(error::Error::InvalidType("number"))?
             }
             Expression::UnaryMinus(x) => {
-                let x = Interpreter::eval_expression(scope.clone(), *x, this.clone())?
-                    .as_number()
-                    .ok_or(error::Error::InvalidType("number"))?;
-                SValue::new(Value::Float(-x))
+                let x = Interpreter::eval_expression(settings, scope.clone(), *x, this.clone())?;
+                match &*x {
+                    Value::Int(n) if *n != i64::MIN => SValue::new(Value::Int(-n)),
+                    // `-9223372036854775808` is a big literal negated back into range
+                    Value::BigInt(digits) => {
+                        let negated = match digits.strip_prefix('-') {
+                            Some(digits) => digits.to_string(),
+                            None => format!("-{digits}"),
+                        };
+                        SValue::new(match negated.parse() {
+                            Ok(n) => Value::Int(n),
+                            Err(_) => Value::BigInt(negated),
+                        })
+                    }
+                    x => SValue::new(Value::Float(
+                        -x.as_number().ok_or(error::Error::InvalidType("number"))?,
+                    )),
+                }
             }
             Expression::Multiply(x, y) => {
-                let (x, y) = eval_number_pair(this.clone(), scope.clone(), *x, *y)?;
-                SValue::new(Value::Float(x * y))
+                let x = Interpreter::eval_expression(settings, scope.clone(), *x, this.clone())?;
+                let y = Interpreter::eval_expression(settings, scope.clone(), *y, this.clone())?;
+                arithmetic(&x, &y, i64::checked_mul, |x, y| x * y)
+                    .ok_or(error::Error::InvalidType("number"))?
             }
             Expression::Divide(x, y) => {
-                let (x, y) = eval_number_pair(this.clone(), scope.clone(), *x, *y)?;
+                let (x, y) = eval_number_pair(settings, this.clone(), scope.clone(), *x, *y)?;
                 SValue::new(Value::Float(x / y))
             }
+            // The result has the sign of the dividend, like `%` in rust
+            Expression::Modulo(x, y) => {
+                let x = Interpreter::eval_expression(settings, scope.clone(), *x, this.clone())?;
+                let y = Interpreter::eval_expression(settings, scope.clone(), *y, this.clone())?;
+                if y.as_number() == Some(0.0) {
+                    return Err(error::Error::BuiltinFunctionError(
+                        "modulo by zero".to_string(),
+                    ));
+                }
+                arithmetic(&x, &y, i64::checked_rem, |x, y| x % y)
+                    .ok_or(error::Error::InvalidType("number"))?
+            }
             Expression::And(x, y) => {
-                let x = Interpreter::eval_expression(scope.clone(), *x, this.clone())?
+                let x = Interpreter::eval_expression(settings, scope.clone(), *x, this.clone())?
                     .as_bool()
                     .ok_or(error::Error::InvalidType("boolean"))?;
                 if x {
-                    Interpreter::eval_expression(scope.clone(), *y, this.clone())?
+                    Interpreter::eval_expression(settings, scope.clone(), *y, this.clone())?
                 } else {
                     SValue::new(Value::Bool(false))
                 }
             }
             Expression::Or(x, y) => {
-                let x = Interpreter::eval_expression(scope.clone(), *x, this.clone())?
+                let x = Interpreter::eval_expression(settings, scope.clone(), *x, this.clone())?
                     .as_bool()
                     .ok_or(error::Error::InvalidType("boolean"))?;
                 if x {
                     SValue::new(Value::Bool(true))
                 } else {
-                    Interpreter::eval_expression(scope.clone(), *y, this.clone())?
+                    Interpreter::eval_expression(settings, scope.clone(), *y, this.clone())?
                 }
             }
+            Expression::Eq(x, y) => {
+                SValue::new(Value::Bool(eval_equal(settings, this, scope, *x, *y)?))
+            }
+            Expression::Ne(x, y) => {
+                SValue::new(Value::Bool(!eval_equal(settings, this, scope, *x, *y)?))
+            }
+            Expression::Lt(x, y) => SValue::new(Value::Bool(
+                eval_ordering(settings, this, scope, *x, *y)?.is_lt(),
+            )),
+            Expression::Gt(x, y) => SValue::new(Value::Bool(
+                eval_ordering(settings, this, scope, *x, *y)?.is_gt(),
+            )),
+            Expression::Le(x, y) => SValue::new(Value::Bool(
+                eval_ordering(settings, this, scope, *x, *y)?.is_le(),
+            )),
+            Expression::Ge(x, y) => SValue::new(Value::Bool(
+                eval_ordering(settings, this, scope, *x, *y)?.is_ge(),
+            )),
 
             Expression::List(l) => SValue::new(Value::List(List {
-                elements: RefCell::new(
+                elements: Lock::new(
                     l.into_iter()
-                        .map(move |e| Interpreter::eval_expression(scope.clone(), e, this.clone()))
+                        .map(move |e| {
+                            Interpreter::eval_expression(settings, scope.clone(), e, this.clone())
+                        })
                         .collect::<Result<_, _>>()?,
                 ),
-                rest: RefCell::new(None),
+                rest: Lock::new(None),
+            })),
+            Expression::Dict(d) => SValue::new(Value::Dict(Dict {
+                elements: Lock::new(
+                    d.into_iter()
+                        .map(move |(k, e)| {
+                            let v = Interpreter::eval_expression(
+                                settings,
+                                scope.clone(),
+                                e,
+                                this.clone(),
+                            )?;
+                            Ok((k, v))
+                        })
+                        .collect::<error::Result<_>>()?,
+                ),
+                rest: Lock::new(None),
             })),
-            Expression::Dict(_) => todo!(),
             Expression::Identifier(name) => {
                 if let Some(value) = scope.0.get(&name) {
                     if let Value::Function(Function { name: name2, .. }) = value.borrow() {
-                        assert_eq!(&name, name2);
+                        // Lambdas can be bound to any name, builtins only to their own
+                        if name2 != "lambda" {
+                            assert_eq!(&name, name2);
+                        }
                         Interpreter::eval_expression(
+                            settings,
                             scope.clone(),
                             Expression::FunctionCall(name, vec![]),
                             this.clone(),
@@ -328,34 +742,294 @@ impl Interpreter {
                 } else {
                     None
                 };
+                let per_element = builtin::PER_ELEMENT.contains(&f.name.as_str());
+                let count = arity + using_this as usize;
                 let args = prefix
                     .into_iter()
-                    .chain(args.into_iter())
-                    .map(|e| Interpreter::eval_expression(scope.clone(), e, this.clone()))
+                    .chain(args)
+                    .enumerate()
+                    .map(|(i, e)| {
+                        let function =
+                            matches!(e, Expression::Lambda(..) | Expression::Identifier(_));
+                        if per_element && i + 1 == count && !function {
+                            Ok(element_function(settings, scope.clone(), e))
+                        } else {
+                            Interpreter::eval_expression(settings, scope.clone(), e, this.clone())
+                        }
+                    })
                     .collect::<error::Result<Vec<_>>>()?;
 
-                (f.implementation)(args)?
+                match (&settings.memo, memo_key(f, &args)) {
+                    (Some(memo), Some(key)) => {
+                        let memo: &Lock<HashMap<_, _>> = memo;
+                        let cached = memo.borrow().get(&key).cloned();
+                        match cached {
+                            Some(result) => result,
+                            None => {
+                                let result = (f.implementation)(args)?;
+                                memo.borrow_mut().insert(key, result.clone());
+                                result
+                            }
+                        }
+                    }
+                    _ => (f.implementation)(args)?,
+                }
             }
+            Expression::Comprehension {
+                body,
+                variable,
+                iterable,
+                condition,
+            } => {
+                let iterable =
+                    Interpreter::eval_expression(settings, scope.clone(), *iterable, this.clone())?;
+                let iterable =
+                    sequence_iter(iterable).ok_or(error::Error::InvalidTypes(&["list", "dict"]))?;
+                let settings = settings.clone();
+                let mapped = iterable.filter_map(move |e| {
+                    let eval = |e: SValue| -> error::Result<Option<SValue>> {
+                        let mut scope = scope.clone();
+                        Shared::make_mut(&mut scope.0).insert(variable.clone(), e);
+                        if let Some(condition) = &condition {
+                            let keep = Interpreter::eval_expression(
+                                &settings,
+                                scope.clone(),
+                                (**condition).clone(),
+                                this.clone(),
+                            )?
+                            .as_bool()
+                            .ok_or(error::Error::InvalidType("boolean"))?;
+                            if !keep {
+                                return Ok(None);
+                            }
+                        }
+                        Interpreter::eval_expression(
+                            &settings,
+                            scope,
+                            (*body).clone(),
+                            this.clone(),
+                        )
+                        .map(Some)
+                    };
+                    e.and_then(eval).transpose()
+                });
+                SValue::new(Value::List(List {
+                    elements: Lock::new(vec![]),
+                    rest: Lock::new(Some(Box::new(mapped))),
+                }))
+            }
+            Expression::Lambda(params, body) => SValue::new(Value::Function(Function {
+                name: "lambda".to_string(),
+                arities: vec![params.len()],
+                implementation: Box::new({
+                    let settings = settings.clone();
+                    move |args: Vec<SValue>| {
+                        // Builtins call their function arguments directly, without the arity check
+                        if args.len() != params.len() {
+                            return Err(error::Error::InvalidArity(
+                                "lambda".to_string(),
+                                args.len(),
+                                vec![params.len()],
+                            ));
+                        }
+                        let mut scope = scope.clone();
+                        let scope_inner = Shared::make_mut(&mut scope.0);
+                        for (param, arg) in params.iter().zip(args) {
+                            scope_inner.insert(param.clone(), arg);
+                        }
+                        Interpreter::eval_expression(
+                            &settings,
+                            scope,
+                            (*body).clone(),
+                            this.clone(),
+                        )
+                    }
+                }),
+            })),
         })
     }
 }
 
+/// A function of one element that evaluates `body` with `%` bound to it, see
+/// [`builtin::PER_ELEMENT`]
+fn element_function(settings: &Settings, scope: Scope, body: Expression) -> SValue {
+    let settings = settings.clone();
+    SValue::new(Value::Function(Function {
+        name: "lambda".to_string(),
+        arities: vec![1],
+        implementation: Box::new(move |mut args: Vec<SValue>| {
+            if args.len() != 1 {
+                return Err(error::Error::InvalidArity(
+                    "lambda".to_string(),
+                    args.len(),
+                    vec![1],
+                ));
+            }
+            Interpreter::eval_expression(&settings, scope.clone(), body.clone(), args.remove(0))
+        }),
+    }))
+}
+
+/// Iterate the elements of a list, or the `[key, value]` pairs of a dict
+fn sequence_iter(value: SValue) -> Option<BoxedRest<SValue>> {
+    match &*value {
+        Value::List(_) => Some(Box::new(List::into_iter(value))),
+        Value::Dict(_) => Some(Box::new(Dict::into_iter(value).map(|r| {
+            r.map(|(k, v)| {
+                SValue::new(Value::List(List {
+                    elements: vec![SValue::new(Value::String(k)), v].into(),
+                    rest: None.into(),
+                }))
+            })
+        }))),
+        _ => None,
+    }
+}
+
+/// Ints stay ints unless the result overflows, otherwise the result is a float. `None` if either
+/// isn't a number.
+fn arithmetic(
+    x: &Value,
+    y: &Value,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Option<SValue> {
+    if let (Value::Int(x), Value::Int(y)) = (x, y) {
+        if let Some(n) = int_op(*x, *y) {
+            return Some(SValue::new(Value::Int(n)));
+        }
+    }
+    Some(SValue::new(Value::Float(float_op(
+        x.as_number()?,
+        y.as_number()?,
+    ))))
+}
+
 fn eval_number_pair(
+    settings: &Settings,
     this: SValue,
     scope: Scope,
     x: Expression,
     y: Expression,
 ) -> error::Result<(f64, f64)> {
     Ok((
-        Interpreter::eval_expression(scope.clone(), x, this.clone())?
+        Interpreter::eval_expression(settings, scope.clone(), x, this.clone())?
             .as_number()
             .ok_or(error::Error::InvalidType("number"))?,
-        Interpreter::eval_expression(scope.clone(), y, this.clone())?
+        Interpreter::eval_expression(settings, scope.clone(), y, this.clone())?
             .as_number()
             .ok_or(error::Error::InvalidType("number"))?,
     ))
 }
 
+/// Numbers are equal by value whether they're integers or floats, containers are realized and
+/// compared element by element. Dicts are equal regardless of key order, the
+/// `deep_equal_ordered` builtin is the order-sensitive comparison.
+fn eval_equal(
+    settings: &Settings,
+    this: SValue,
+    scope: Scope,
+    x: Expression,
+    y: Expression,
+) -> error::Result<bool> {
+    let x = Interpreter::eval_expression(settings, scope.clone(), x, this.clone())?;
+    let y = Interpreter::eval_expression(settings, scope.clone(), y, this.clone())?;
+    builtin::equal(&x, &y)
+}
+
+/// What a builtin call is cached under when memoizing. Lambdas are left out since their name
+/// doesn't say what they do, and so are arguments that would have to be realized to be compared.
+fn memo_key(f: &Function, args: &[SValue]) -> Option<String> {
+    if f.name == "lambda"
+        || builtin::UNCACHEABLE.contains(&f.name.as_str())
+        || !args.iter().all(|a| a.is_realized())
+    {
+        return None;
+    }
+    let args = args
+        .iter()
+        .map(|a| builtin::exact_key(a))
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("{}({})", f.name, args.join(", ")))
+}
+
+/// Only numbers and strings can be ordered, see [`builtin::compare`]
+fn eval_ordering(
+    settings: &Settings,
+    this: SValue,
+    scope: Scope,
+    x: Expression,
+    y: Expression,
+) -> error::Result<std::cmp::Ordering> {
+    let x = Interpreter::eval_expression(settings, scope.clone(), x, this.clone())?;
+    let y = Interpreter::eval_expression(settings, scope.clone(), y, this.clone())?;
+    builtin::compare(&x, &y)
+}
+
+impl ExecutedCommand {
+    fn outline_into(&self, depth: usize, result: &mut Vec<String>) {
+        let indent = "  ".repeat(depth);
+        match self {
+            ExecutedCommand::Simple { command } => result.push(format!("{indent}{command}")),
+            ExecutedCommand::Group {
+                enter_kv,
+                commands,
+                leave_kv,
+                ..
+            } => {
+                result.push(format!("{indent}{}", Command::ShiftRight(enter_kv.clone())));
+                for command in commands {
+                    command.outline_into(depth + 1, result);
+                }
+                result.push(format!("{indent}{}", Command::ShiftLeft(leave_kv.clone())));
+            }
+        }
+    }
+
+    fn flatten_into(&self, result: &mut Vec<Command>) {
+        match self {
+            ExecutedCommand::Simple { command } => result.push(command.clone()),
+            ExecutedCommand::Group {
+                enter_kv,
+                commands,
+                leave_kv,
+                ..
+            } => {
+                result.push(Command::ShiftRight(enter_kv.clone()));
+                for command in commands {
+                    command.flatten_into(result);
+                }
+                result.push(Command::ShiftLeft(leave_kv.clone()));
+            }
+        }
+    }
+}
+
+impl Tracer {
+    fn enter(&self, e: &Expression) {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed);
+        // Tracing is best effort, a failed write shouldn't fail the evaluation
+        let _ = writeln!(self.out.borrow_mut(), "{}{}", "  ".repeat(depth), e);
+    }
+
+    fn leave(&self, result: &error::Result<SValue>) {
+        let depth = self.depth.fetch_sub(1, Ordering::Relaxed) - 1;
+        let indent = "  ".repeat(depth);
+        let _ = match result {
+            Ok(value) => writeln!(self.out.borrow_mut(), "{indent}=> {value}"),
+            Err(err) => writeln!(self.out.borrow_mut(), "{indent}=> error: {err}"),
+        };
+    }
+}
+
+impl std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Tracer")
+            .field("depth", &self.depth)
+            .finish()
+    }
+}
+
 impl Program {
     fn value(&self) -> SValue {
         let (initial, commands) = match self {
@@ -382,6 +1056,34 @@ impl Program {
         .clone()
     }
 
+    fn scope_mut(&mut self) -> &mut Scope {
+        match self {
+            Program::Closed { scope, .. } => scope,
+            Program::Open { scope, .. } => scope,
+        }
+    }
+
+    fn reset_scope(&mut self) {
+        let mut scope = builtin::builtin_functions();
+        if let Program::Open {
+            history,
+            kv: Some((k, v)),
+            scope: Scope(old),
+            ..
+        } = self
+        {
+            for name in [k, v] {
+                if let Some(value) = old.get(name) {
+                    scope.insert(name.clone(), value.clone());
+                }
+            }
+            history.reset_scope();
+        } else if let Program::Open { history, .. } = self {
+            history.reset_scope();
+        }
+        *self.scope_mut() = Scope(Shared::new(scope));
+    }
+
     pub fn push(&mut self, command: CachedCommand) {
         let commands = match self {
             Program::Closed { commands, .. } => commands,
@@ -393,11 +1095,84 @@ impl Program {
     pub fn pop(&mut self) {
         let commands = match self {
             Program::Closed { commands, .. } => commands,
+            // Undoing a shift-right with nothing run inside it leaves the shift
+            Program::Open { commands, .. } if commands.is_empty() => {
+                replace_with::replace_with_or_abort(self, |p| {
+                    let Program::Open { history, .. } = p else {
+                        unreachable!("matched an open program")
+                    };
+                    *history
+                });
+                return;
+            }
             Program::Open { commands, .. } => commands,
         };
-        // TODO: undo just the shift-left by replacing self with the
-        // `commands` and history and stuff
-        commands.pop();
+        let Some(CachedCommand {
+            command: ExecutedCommand::Group { name, enter_kv, .. },
+            inside: Some(inside),
+            ..
+        }) = commands.pop()
+        else {
+            return;
+        };
+        // Undoing a shift-left puts us back inside the shift
+        let Inside {
+            initial,
+            scope,
+            commands,
+        } = *inside;
+        replace_with::replace_with_or_abort(self, |history| Program::Open {
+            name,
+            kv: enter_kv,
+            history: Box::new(history),
+
+            initial,
+            scope,
+            commands,
+        });
+    }
+
+    /// All the commands that led to the current state, with groups flattened into shifts
+    fn history(&self) -> Vec<Command> {
+        let (mut result, commands) = match self {
+            Program::Closed { commands, .. } => (vec![], commands),
+            Program::Open {
+                kv,
+                history,
+                commands,
+                ..
+            } => {
+                let mut result = history.history();
+                result.push(Command::ShiftRight(kv.clone()));
+                (result, commands)
+            }
+        };
+        for command in commands {
+            command.command.flatten_into(&mut result);
+        }
+        result
+    }
+
+    /// The outline lines and how deep the shifts that are still open go
+    fn outline(&self) -> (Vec<String>, usize) {
+        let (mut result, depth, commands) = match self {
+            Program::Closed { commands, .. } => (vec![], 0, commands),
+            Program::Open {
+                kv,
+                history,
+                commands,
+                ..
+            } => {
+                let (mut result, depth) = history.outline();
+                let indent = "  ".repeat(depth);
+                result.push(format!("{indent}{}", Command::ShiftRight(kv.clone())));
+                (result, depth + 1, commands)
+            }
+        };
+        for command in commands {
+            command.command.outline_into(depth, &mut result);
+        }
+        (result, depth)
     }
 
     fn status(&self) -> Vec<String> {
@@ -431,7 +1206,15 @@ mod test {
         interpreter.run(command("json").unwrap()).unwrap();
         interpreter.run(command(">>").unwrap()).unwrap();
         assert_eq!(&*interpreter.value(), &Value::Int(1));
+        assert!(matches!(
+            interpreter.run(command(">>").unwrap()),
+            Err(error::Error::InvalidTypes(_))
+        ));
         interpreter.run(command("<<").unwrap()).unwrap();
+        assert!(matches!(
+            interpreter.run(command(">> k:v").unwrap()),
+            Err(error::Error::ShiftRightListWithKeys)
+        ));
         interpreter.value().sample().unwrap();
         assert_eq!(
             &*interpreter.value(),
@@ -462,6 +1245,467 @@ mod test {
         interpreter.run(command("<<").unwrap()).unwrap();
         interpreter.run(command("<<").unwrap()).unwrap();
         interpreter.value().sample().unwrap();
-        panic!("{:?}", interpreter.value());
+        let row = || {
+            SValue::new(Value::List(List {
+                elements: vec![
+                    SValue::new(Value::Int(100)),
+                    SValue::new(Value::Int(100)),
+                    SValue::new(Value::Int(100)),
+                ]
+                .into(),
+                rest: None.into(),
+            }))
+        };
+        assert_eq!(
+            &*interpreter.value(),
+            &Value::List(List {
+                elements: vec![row(), row(), row()].into(),
+                rest: None.into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_int_arithmetic() {
+        let mut interpreter = Interpreter::new("".into());
+        for (c, expected) in [
+            ("3 - 5", Value::Int(-2)),
+            ("-5", Value::Int(-5)),
+            ("2 * -3 + 1", Value::Int(-5)),
+            ("1 - 0.5", Value::Float(0.5)),
+            ("4 / 2", Value::Float(2.0)),
+            ("7 %% 3", Value::Int(1)),
+            ("-7 %% 3", Value::Int(-1)),
+            ("7.5 %% 2", Value::Float(1.5)),
+            // Overflowing falls back to a float
+            (
+                "9223372036854775807 + 1",
+                Value::Float(9223372036854775808.0),
+            ),
+            ("-9223372036854775807 - 1", Value::Int(i64::MIN)),
+            (
+                "-(-9223372036854775807 - 1)",
+                Value::Float(9223372036854775808.0),
+            ),
+            // Literals too big for `Int` are kept exact
+            ("-9223372036854775808", Value::Int(i64::MIN)),
+            (
+                "9223372036854775808",
+                Value::BigInt("9223372036854775808".into()),
+            ),
+            (
+                "-98765432109876543210",
+                Value::BigInt("-98765432109876543210".into()),
+            ),
+        ] {
+            interpreter.run(command(c).unwrap()).unwrap();
+            assert_eq!(&*interpreter.value(), &expected, "{c}");
+        }
+        for zero in ["7 %% 0", "7 %% 0.0"] {
+            assert!(matches!(
+                interpreter.run(command(zero).unwrap()).unwrap_err(),
+                error::Error::BuiltinFunctionError(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_plus_containers() {
+        let mut interpreter = Interpreter::new("[1, 2]".into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter.run(command("% + [3] + %").unwrap()).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(
+            &*interpreter.value(),
+            &Value::List(List {
+                elements: vec![
+                    SValue::new(Value::Int(1)),
+                    SValue::new(Value::Int(2)),
+                    SValue::new(Value::Int(3)),
+                    SValue::new(Value::Int(1)),
+                    SValue::new(Value::Int(2)),
+                ]
+                .into(),
+                rest: None.into(),
+            })
+        );
+
+        let mut interpreter = Interpreter::new(r#"[{"a": 1, "b": 2}, {"b": 3, "c": 4}]"#.into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter
+            .run(command("(get % 0) + (get % 1)").unwrap())
+            .unwrap();
+        let value = interpreter.value();
+        let dict = value.as_dict().unwrap().elements.borrow();
+        assert_eq!(
+            dict.iter()
+                .map(|(k, v)| (k.as_str(), v.as_number().unwrap()))
+                .collect::<Vec<_>>(),
+            vec![("a", 1.0), ("b", 3.0), ("c", 4.0)]
+        );
+
+        assert!(matches!(
+            interpreter.run(command("[1] + 1").unwrap()),
+            Err(error::Error::InvalidTypes(_))
+        ));
+    }
+
+    #[test]
+    fn test_trace() {
+        let out = Shared::new(Lock::new(Vec::<u8>::new()));
+        let mut interpreter = Interpreter::new("".into());
+        let Command::Expression(inc) = command("|x| x + 1").unwrap() else {
+            unreachable!("a lambda is an expression")
+        };
+        interpreter.define("inc".into(), inc).unwrap();
+        *interpreter.settings.trace.borrow_mut() = Some(Tracer {
+            depth: Shared::new(AtomicUsize::new(0)),
+            out: out.clone(),
+        });
+        interpreter.run(command("1 + 2").unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(Lock::borrow(&out).clone()).unwrap(),
+            "(1 + 2)
+  1
+  => 1
+  2
+  => 2
+=> 3
+"
+        );
+
+        // The lambda was made before tracing was turned on, its body is traced too
+        Lock::borrow_mut(&out).clear();
+        interpreter.run(command("inc 1").unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(Lock::borrow(&out).clone()).unwrap(),
+            "(inc 1)
+  1
+  => 1
+  (x + 1)
+    x
+    => 1
+    1
+    => 1
+  => 2
+=> 2
+"
+        );
+
+        // Lazy results are shown without realizing them
+        Lock::borrow_mut(&out).clear();
+        interpreter.run(command("cycle [1]").unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(Lock::borrow(&out).clone()).unwrap(),
+            "(cycle [1])
+  [1]
+    1
+    => 1
+  => [1]
+=> [...]
+"
+        );
+        let value = interpreter.value();
+        assert!(value.as_list().unwrap().elements.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut interpreter = Interpreter::new("[1, 2, 3]".into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter.run(command(">>").unwrap()).unwrap();
+        interpreter.checkpoint("inside".into());
+        interpreter.run(command("% * 2").unwrap()).unwrap();
+        interpreter.run(command("<<").unwrap()).unwrap();
+        interpreter.run(command("0").unwrap()).unwrap();
+
+        interpreter.restore("inside").unwrap();
+        assert_eq!(interpreter.status(), vec!["list ()"]);
+        assert_eq!(&*interpreter.value(), &Value::Int(1));
+
+        // Undoing past the checkpoint and running something else doesn't change what it restores
+        interpreter.run(command("% * 2").unwrap()).unwrap();
+        interpreter.checkpoint("doubled".into());
+        interpreter.undo();
+        interpreter.run(command("% + 100").unwrap()).unwrap();
+        interpreter.restore("doubled").unwrap();
+        assert_eq!(&*interpreter.value(), &Value::Int(2));
+
+        assert!(matches!(
+            interpreter.restore("missing"),
+            Err(error::Error::CheckpointNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_comprehension() {
+        let mut interpreter = Interpreter::new(
+            r#"[{"name": "a", "active": true}, {"name": "b", "active": false}, {"name": "c", "active": true}]"#
+                .into(),
+        );
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter
+            .run(command(r#"[(get r "name") + "!" for r in % if get r "active"]"#).unwrap())
+            .unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(
+            &*interpreter.value(),
+            &Value::List(List {
+                elements: vec![
+                    SValue::new(Value::String("a!".into())),
+                    SValue::new(Value::String("c!".into())),
+                ]
+                .into(),
+                rest: None.into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut interpreter = Interpreter::new("[1, 2]".into());
+        interpreter.set_timeout(Some(10));
+        interpreter.run(command("json").unwrap()).unwrap();
+        // Lazy, so it doesn't need to finish
+        interpreter.run(command("cycle").unwrap()).unwrap();
+        assert!(matches!(
+            interpreter.run(command("sorted").unwrap()),
+            Err(error::Error::Timeout)
+        ));
+
+        let value = interpreter.value();
+        let timeout = Some(std::time::Duration::from_millis(10));
+        assert!(matches!(
+            with_deadline(timeout, || value.realize()),
+            Err(error::Error::Timeout)
+        ));
+        // Whatever was realized before timing out is kept, and the rest is still lazy
+        assert!(value.as_list().unwrap().rest.borrow().is_some());
+        assert!(with_deadline(timeout, || value.sample()).is_ok());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let eval = |source: &str| {
+            let mut interpreter = Interpreter::new("".into());
+            interpreter.run(command(source).unwrap())?;
+            Ok::<_, error::Error>(interpreter.value())
+        };
+        let bool = |b| SValue::new(Value::Bool(b));
+
+        assert_eq!(eval("3 > 2").unwrap(), bool(true));
+        assert_eq!(eval(r#""a" < "b""#).unwrap(), bool(true));
+        assert_eq!(eval("1 == 1.0").unwrap(), bool(true));
+        assert_eq!(eval("2 <= 1 + 1").unwrap(), bool(true));
+        assert_eq!(eval("2 >= 3").unwrap(), bool(false));
+        assert_eq!(eval(r#""1" != 1"#).unwrap(), bool(true));
+        assert_eq!(eval("null == null").unwrap(), bool(true));
+        assert_eq!(eval("true != false").unwrap(), bool(true));
+        assert_eq!(eval("[1, 2] == [1, 2]").unwrap(), bool(true));
+        assert_eq!(eval("[1, [2]] == [1.0, [2.0]]").unwrap(), bool(true));
+        assert_eq!(
+            eval(r#"{"a": 1, "b": 2} == {"b": 2.0, "a": 1.0}"#).unwrap(),
+            bool(true)
+        );
+        assert_eq!(eval("[1] != [1.5]").unwrap(), bool(true));
+        assert!(matches!(
+            eval("true < false"),
+            Err(error::Error::InvalidType(_))
+        ));
+    }
+
+    #[test]
+    fn test_dict_literal() {
+        let mut interpreter = Interpreter::new("[1, 2]".into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter
+            .run(command(r#"{ "b": 1 + 1, "a": %, "c": {"d": null} }"#).unwrap())
+            .unwrap();
+        assert_eq!(
+            interpreter.value().to_string(),
+            "{b: 2, a: [1, 2], c: {d: null}}"
+        );
+    }
+
+    #[test]
+    fn test_shift_dict_kv() {
+        let mut interpreter = Interpreter::new(r#"{"a": 1, "b": 2}"#.into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter.run(command(">> key: val").unwrap()).unwrap();
+        interpreter.run(command(r#"key + "=""#).unwrap()).unwrap();
+        assert_eq!(&*interpreter.value(), &Value::String("a=".into()));
+        interpreter.run(command("[%, val * 10]").unwrap()).unwrap();
+        interpreter.run(command("<<").unwrap()).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(
+            interpreter.value().to_string(),
+            r#"[["a=", 10], ["b=", 20]]"#
+        );
+
+        // Without names the entry is bound to `k` and `v`
+        for _ in 0..4 {
+            interpreter.undo();
+        }
+        assert!(interpreter.status().is_empty());
+        interpreter.run(command(">>").unwrap()).unwrap();
+        interpreter.run(command("v").unwrap()).unwrap();
+        interpreter.run(command("<<").unwrap()).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(interpreter.value().to_string(), "[1, 2]");
+    }
+
+    #[test]
+    fn test_shift_left_into_dict() {
+        let mut interpreter =
+            Interpreter::new(r#"[{"name": "a", "n": 1}, {"name": "b", "n": 2}]"#.into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter.run(command(">>").unwrap()).unwrap();
+        interpreter
+            .run(command(r#"assoc % "n" ((get % "n") * 10)"#).unwrap())
+            .unwrap();
+        interpreter
+            .run(command(r#"<< get % "name": get % "n""#).unwrap())
+            .unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(interpreter.value().to_string(), "{a: 10, b: 20}");
+
+        for _ in 0..3 {
+            interpreter.undo();
+        }
+        interpreter.run(command(">>").unwrap()).unwrap();
+        interpreter
+            .run(command(r#"<< get % "n": %"#).unwrap())
+            .unwrap();
+        assert!(matches!(
+            interpreter.value().realize(),
+            Err(error::Error::InvalidType("string"))
+        ));
+    }
+
+    #[test]
+    fn test_undo_shift_left() {
+        let mut interpreter = Interpreter::new("[1, 2, 3]".into());
+        interpreter.run(command("json").unwrap()).unwrap();
+        interpreter.run(command(">>").unwrap()).unwrap();
+        interpreter.run(command("% * 2").unwrap()).unwrap();
+        interpreter.run(command("<<").unwrap()).unwrap();
+        assert!(interpreter.status().is_empty());
+
+        interpreter.undo();
+        assert_eq!(interpreter.status(), vec!["list ()"]);
+        assert_eq!(
+            interpreter.program.history(),
+            vec![
+                command("json").unwrap(),
+                command(">>").unwrap(),
+                command("% * 2").unwrap()
+            ]
+        );
+        assert_eq!(&*interpreter.value(), &Value::Int(2));
+
+        // Leaving again gives the same result as before the undo
+        interpreter.run(command("<<").unwrap()).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(interpreter.value().to_string(), "[2, 4, 6]");
+        // Undoing everything inside the shift and then the shift itself gets back out
+        interpreter.undo();
+        interpreter.undo();
+        interpreter.undo();
+        assert!(interpreter.status().is_empty());
+        assert_eq!(
+            interpreter.program.history(),
+            vec![command("json").unwrap()]
+        );
+        assert_eq!(interpreter.value().to_string(), "[1, 2, 3]");
+    }
+
+    #[test]
+    fn test_outline() {
+        let mut interpreter = Interpreter::new(r#"{"a": [1, 2], "b": [3]}"#.into());
+        for c in [
+            "json", ">> k:v", "v", ">>", "% + 1", "<<", "len %", "<<", ">>", "% * 2",
+        ] {
+            interpreter.run(command(c).unwrap()).unwrap();
+        }
+        assert_eq!(
+            interpreter.outline(),
+            vec![
+                "json",
+                ">> k:v",
+                "  v",
+                "  >>",
+                "    (% + 1)",
+                "  <<",
+                "  (len %)",
+                "<<",
+                ">>",
+                "  (% * 2)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memoize() {
+        let calls = Shared::new(AtomicUsize::new(0));
+        let mut interpreter = Interpreter::new("".into());
+        let counted = {
+            let calls = calls.clone();
+            move |mut args: Vec<SValue>| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Ok(args.remove(0))
+            }
+        };
+        Shared::make_mut(&mut interpreter.program.scope_mut().0).insert(
+            "counted".to_string(),
+            SValue::new(Value::Function(Function {
+                name: "counted".to_string(),
+                arities: vec![1],
+                implementation: Box::new(counted),
+            })),
+        );
+
+        let repeated = command(r#"[counted [1, "a"], counted [1, "a"], counted 2]"#).unwrap();
+        interpreter.run(repeated.clone()).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        interpreter.set_memoize(true);
+        interpreter.run(repeated.clone()).unwrap();
+        interpreter.run(repeated).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 5);
+        assert_eq!(
+            interpreter.value().to_string(),
+            r#"[[1, "a"], [1, "a"], 2]"#
+        );
+    }
+
+    #[test]
+    fn test_memoize_tells_values_apart() {
+        let mut interpreter = Interpreter::new("".into());
+        interpreter.set_memoize(true);
+        let mut eval = |cmd: &str| {
+            interpreter.run(command(cmd).unwrap()).unwrap();
+            interpreter.value().realize().unwrap();
+            interpreter.value().to_string()
+        };
+
+        assert_eq!(eval(r#"keys {"a": 1, "b": 2}"#), r#"["a", "b"]"#);
+        assert_eq!(eval(r#"keys {"b": 2, "a": 1}"#), r#"["b", "a"]"#);
+
+        assert_eq!(
+            eval(r#"deep_equal_ordered {"a": 1, "b": 2} {"a": 1, "b": 2}"#),
+            "true"
+        );
+        assert_eq!(
+            eval(r#"deep_equal_ordered {"a": 1, "b": 2} {"b": 2, "a": 1}"#),
+            "false"
+        );
+
+        assert_eq!(eval(r#"type "2024-01-02T00:00:00+00:00""#), r#""string""#);
+        assert_eq!(
+            eval(r#"type (parse_date "2024-01-02" "%Y-%m-%d")"#),
+            r#""date""#
+        );
+        assert_eq!(eval("type 1"), r#""int""#);
+        assert_eq!(eval("type 1.0"), r#""float""#);
     }
 }
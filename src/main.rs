@@ -3,8 +3,12 @@
 #![allow(unused_variables)]
 
 use anyhow::{Context, Result};
-use interpreter::Interpreter;
-use std::io::{stdin, stdout, BufRead, Write};
+use data::{with_deadline, List, SValue, Value};
+use interpreter::{ErrorMode, Interpreter};
+use parser::{Command, Expression};
+use std::io::{stderr, stdin, stdout, BufRead, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
 
 mod builtin;
 mod data;
@@ -12,25 +16,196 @@ mod error;
 mod interpreter;
 mod parser;
 
-fn main() -> Result<()> {
-    run_prompt()
+fn main() {
+    if let Err(err) = start() {
+        eprintln!("{}", render_error(err));
+        std::process::exit(1);
+    }
+}
+
+fn start() -> Result<()> {
+    let args = cli().get_matches();
+    let file = args.get_one::<String>("file").map(String::as_str);
+    let format = args.get_one::<String>("input-format").map(String::as_str);
+    let follow = args.get_one::<String>("follow").map(String::as_str);
+    let expressions: Vec<_> = args
+        .get_many::<String>("expression")
+        .map(|e| e.cloned().collect())
+        .unwrap_or_default();
+
+    // Piped input is the value to work on when the commands are given with -e, otherwise it's
+    // the commands, like typing them at the prompt
+    let mut interpreter = if file.is_none()
+        && follow.is_none()
+        && !expressions.is_empty()
+        && !stdin().is_terminal()
+    {
+        let mut input = String::new();
+        stdin()
+            .read_to_string(&mut input)
+            .context("reading stdin")?;
+        let format = format.unwrap_or("json");
+        load(input, format).with_context(|| format!("parsing stdin as {format}"))?
+    } else {
+        seed(file, format)?
+    };
+
+    let init = match args.get_one::<String>("init") {
+        Some(init) => Some(std::path::PathBuf::from(init)),
+        None => std::env::var_os("HOME")
+            .map(|home| std::path::Path::new(&home).join(".pilangrc"))
+            .filter(|rc| rc.exists()),
+    };
+    if let Some(init) = init {
+        if let Err(err) = run_script(&init, &mut interpreter) {
+            eprintln!("{}", render_error(err));
+        }
+    }
+
+    if let Some(path) = follow {
+        return follow_ndjson(&interpreter, path, &expressions);
+    }
+
+    if !expressions.is_empty() {
+        for expression in expressions {
+            run(expression.clone(), &mut interpreter)
+                .with_context(|| format!("running `{expression}`"))?;
+        }
+        // An element that fails to realize fails the run, instead of being shown as a marker
+        let value = interpreter.value();
+        with_deadline(interpreter.timeout(), || value.realize()).context("realizing the result")?;
+        run(".done".into(), &mut interpreter)?;
+        return Ok(());
+    }
+
+    run_prompt(interpreter)
+}
+
+fn cli() -> clap::Command {
+    clap::Command::new("pi")
+        .arg(clap::Arg::new("file").help("File to load as the initial value"))
+        .arg(
+            clap::Arg::new("input-format")
+                .long("input-format")
+                .value_parser(["json", "json5", "yaml", "csv", "ndjson", "raw"])
+                .help("How to parse the file, instead of guessing from its extension"),
+        )
+        .arg(
+            clap::Arg::new("expression")
+                .short('e')
+                .long("expression")
+                .action(clap::ArgAction::Append)
+                .help("Run the command and print the result instead of starting the prompt. Can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("follow")
+                .long("follow")
+                .value_name("FILE")
+                .conflicts_with("file")
+                .help("Run the -e commands on each record of an NDJSON file, including ones appended later, until Ctrl-C"),
+        )
+        .arg(
+            clap::Arg::new("init")
+                .long("init")
+                .help("Commands to run before the prompt starts [default: ~/.pilangrc]"),
+        )
+}
+
+/// The error's message, followed by what caused it on its own lines
+fn render_error(err: impl Into<anyhow::Error>) -> String {
+    let err = err.into();
+    let mut chain = err.chain();
+    let mut rendered = format!(
+        "Error: {}",
+        chain.next().expect("an error is its own cause")
+    );
+    for cause in chain {
+        rendered.push_str(&format!("\n  caused by: {cause}"));
+    }
+    rendered
+}
+
+/// Run each line of the file as if it was typed at the prompt, stopping at the first one that
+/// fails. The lines before it stay run.
+fn run_script(path: &std::path::Path, interpreter: &mut Interpreter) -> Result<()> {
+    let script =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    for (number, line) in script.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let keep_going = run(line.to_string(), interpreter)
+            .with_context(|| format!("line {} of {}", number + 1, path.display()))?;
+        if !keep_going {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Start an interpreter on the file's contents, parsed according to the format
+fn seed(file: Option<&str>, format: Option<&str>) -> Result<Interpreter> {
+    let Some(file) = file else {
+        return Ok(Interpreter::new(
+            "{\"a\": 1, \"b\": 2.0, \"c\": [1,2,3], \"d\": null}".into(),
+        ));
+    };
+    let input = std::fs::read_to_string(file).with_context(|| format!("reading {file}"))?;
+    let format = format.unwrap_or_else(|| {
+        match std::path::Path::new(file)
+            .extension()
+            .and_then(|e| e.to_str())
+        {
+            Some("json") => "json",
+            Some("json5") => "json5",
+            Some("yaml" | "yml") => "yaml",
+            Some("csv") => "csv",
+            Some("ndjson" | "jsonl") => "ndjson",
+            _ => "raw",
+        }
+    });
+    load(input, format).with_context(|| format!("parsing {file} as {format}"))
 }
 
-fn run_prompt() -> Result<()> {
-    let mut interpreter =
-        Interpreter::new("{\"a\": 1, \"b\": 2.0, \"c\": [1,2,3], \"d\": null}".into());
+/// Start an interpreter on the text, parsed according to the format
+fn load(input: String, format: &str) -> Result<Interpreter> {
+    let loader = match format {
+        "json" => Some("json"),
+        "json5" => Some("json5"),
+        "yaml" => Some("yaml"),
+        "csv" => Some("from_csv"),
+        "ndjson" => Some("from_ndjson"),
+        _ => None,
+    };
+
+    // Loading is a regular command, so it can be undone to get the raw text back
+    let mut interpreter = Interpreter::new(input);
+    if let Some(loader) = loader {
+        interpreter.run(parser::command(loader)?)?;
+    }
+    Ok(interpreter)
+}
 
+/// Run the lines of stdin. When they're piped in as a script, there's no prompt and the first
+/// error stops it.
+fn run_prompt(mut interpreter: Interpreter) -> Result<()> {
     let stdin = stdin();
+    let script = !stdin.is_terminal();
     let stdin = stdin.lock();
-    prompt(&interpreter);
+    if !script {
+        install_interrupt_handler()?;
+        prompt(&interpreter, None, &mut stdout(), &mut stderr())?;
+    }
     for line in stdin.lines() {
         if let Ok(line) = line {
-            match run(line, &mut interpreter) {
-                Ok(true) => {}
-                Ok(false) => break,
-                Err(err) => eprintln!("Error: {:#?}", err),
+            let keep_going = if script {
+                run(line, &mut interpreter)?
+            } else {
+                interact(line, &mut interpreter, &mut stdout(), &mut stderr())?
+            };
+            if !keep_going {
+                break;
             }
-            prompt(&interpreter);
         } else {
             println!("End of input. Goodbye!");
             break;
@@ -39,16 +214,108 @@ fn run_prompt() -> Result<()> {
     Ok(())
 }
 
-fn prompt(interpreter: &Interpreter) {
+/// Run a line typed at the prompt, then show the prompt again. In diff mode it shows what the
+/// line changed, if it ran. Ctrl-C stops the line instead of exiting.
+fn interact(
+    line: String,
+    interpreter: &mut Interpreter,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> Result<bool> {
+    data::clear_interrupt();
+    let previous = interpreter.value();
+    let previous = match run(line, interpreter) {
+        Ok(true) => Some(previous),
+        Ok(false) => return Ok(false),
+        Err(e) => {
+            writeln!(err, "{}", render_error(e))?;
+            None
+        }
+    };
+    prompt(interpreter, previous, out, err)?;
+    Ok(true)
+}
+
+fn prompt(
+    interpreter: &Interpreter,
+    previous: Option<SValue>,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> std::io::Result<()> {
+    if interpreter.compact() {
+        match with_deadline(interpreter.timeout(), || compact_line(interpreter)) {
+            Ok(line) => write!(out, "{line} $> ")?,
+            Err(e) => {
+                writeln!(err, "{}", render_error(e))?;
+                write!(out, "$> ")?;
+            }
+        }
+        return out.flush();
+    }
+
     let status = interpreter.status();
+    let val = with_deadline(interpreter.timeout(), || render_value(interpreter));
+    writeln!(out, "{}", status.join(" >> "))?;
+    if let Some(previous) = previous.filter(|_| interpreter.diff_mode()) {
+        let width = interpreter.sample_width();
+        match with_deadline(interpreter.timeout(), || {
+            diff(&previous, &interpreter.value(), width)
+        }) {
+            Ok(lines) => {
+                for line in lines {
+                    writeln!(out, "{}", truncate(&line, interpreter.terminal_width()))?;
+                }
+            }
+            Err(e) => writeln!(err, "{}", render_error(e))?,
+        }
+    }
+    match val {
+        Ok(val) => writeln!(out, "{val}")?,
+        Err(e) => writeln!(err, "{}", render_error(e))?,
+    }
+    write!(out, "$> ")?;
+    out.flush()
+}
+
+/// The status and the value preview together on one line, cut to the terminal width
+fn compact_line(interpreter: &Interpreter) -> error::Result<String> {
+    let value = render_value(interpreter)?;
+    let status = interpreter.status();
+    let line = if status.is_empty() {
+        value
+    } else {
+        format!("[{}] {value}", status.join(" >> "))
+    };
+    Ok(truncate(&line, interpreter.terminal_width()))
+}
+
+/// Show as much of the value as gets realized for the preview, cut to the terminal width
+fn render_value(interpreter: &Interpreter) -> error::Result<String> {
     let val = interpreter.value();
-    if let Err(err) = val.sample() {
-        eprintln!("Error: {:#?}", err);
+    let width = interpreter.sample_width();
+    let rendered = match interpreter.error_mode() {
+        ErrorMode::Strict => {
+            val.sample_n(width)?;
+            val.sampled(width).to_string()
+        }
+        ErrorMode::Lenient => val.render_lenient(width),
     };
-    println!("{}", status.join(" >> "));
-    println!("{val}");
-    print!("$> ");
-    stdout().flush().unwrap();
+    Ok(truncate(&rendered, interpreter.terminal_width()))
+}
+
+/// Cut each line to `width` characters, marking the cut lines with `...`
+fn truncate(text: &str, width: usize) -> String {
+    text.lines()
+        .map(|line| {
+            if line.chars().count() <= width {
+                line.to_string()
+            } else {
+                let kept: String = line.chars().take(width.saturating_sub(3)).collect();
+                format!("{kept}...")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 fn run(line: String, interpreter: &mut Interpreter) -> Result<bool> {
@@ -57,19 +324,1137 @@ fn run(line: String, interpreter: &mut Interpreter) -> Result<bool> {
         parser::UserInput::Command(command) => {
             interpreter.run(command).context("running command")?
         }
-        parser::UserInput::Directive(name, _) => match name.as_str() {
+        parser::UserInput::Explain(command) => println!("{}", explain(interpreter, &command)),
+        parser::UserInput::BatchApply(command) => {
+            let width = interpreter
+                .pins()
+                .keys()
+                .map(String::len)
+                .max()
+                .unwrap_or(0);
+            for (name, result) in batch_apply(interpreter, command) {
+                let row = match result.and_then(|forked| render_value(&forked)) {
+                    Ok(val) => format!("{name:width$} | {val}"),
+                    Err(err) => format!("{name:width$} | Error: {err}"),
+                };
+                println!("{}", truncate(&row, interpreter.terminal_width()));
+            }
+        }
+        parser::UserInput::Directive(name, args) => match name.as_str() {
             "undo" | "u" => interpreter.undo(),
             "exit" | "quit" | "q" => return Ok(false),
             "done" | "d" => {
                 let val = interpreter.value();
-                if let Err(err) = val.realize() {
-                    eprintln!("Error: {:#?}", err);
-                };
-                println!("{val}");
+                match interpreter.error_mode() {
+                    ErrorMode::Strict => {
+                        match with_deadline(interpreter.timeout(), || val.realize()) {
+                            Ok(()) => println!("{val}"),
+                            Err(err) => eprintln!("{}", render_error(err)),
+                        }
+                    }
+                    ErrorMode::Lenient => println!("{}", val.render_lenient(usize::MAX)),
+                }
                 return Ok(false);
             }
-            _ => eprintln!("Unknown directive `{}`", name),
+            "errmode" => interpreter.set_error_mode(match name_arg(&args)? {
+                "strict" => ErrorMode::Strict,
+                "lenient" => ErrorMode::Lenient,
+                _ => anyhow::bail!("expected `strict` or `lenient`"),
+            }),
+            "json-stream" => {
+                json_stream(interpreter.value(), &mut stdout()).context("streaming JSON")?
+            }
+            "save-output" => {
+                let (path, append) = match args.as_slice() {
+                    [path] => (path, false),
+                    [path, Expression::Identifier(flag)] if flag == "--append" => (path, true),
+                    _ => anyhow::bail!("usage: .save-output PATH [--append]"),
+                };
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(append)
+                    .truncate(!append)
+                    .open(string_arg(path)?)
+                    .context("opening output file")?;
+                json_stream(interpreter.value(), &mut std::io::BufWriter::new(file))
+                    .context("saving output")?
+            }
+            "trace" => interpreter.set_trace(toggle(&args)?),
+            "memoize" => interpreter.set_memoize(toggle(&args)?),
+            "checkpoint" => interpreter.checkpoint(name_arg(&args)?.to_string()),
+            "timeout" => interpreter.set_timeout(match args.as_slice() {
+                [Expression::Identifier(off)] if off == "off" => None,
+                args => Some(int_arg(args)? as u64),
+            }),
+            "source" | "include" => {
+                let [path] = args.as_slice() else {
+                    anyhow::bail!("usage: .source PATH");
+                };
+                if SOURCE_DEPTH.fetch_add(1, Ordering::SeqCst) >= MAX_SOURCE_DEPTH {
+                    SOURCE_DEPTH.fetch_sub(1, Ordering::SeqCst);
+                    anyhow::bail!("scripts sourced more than {MAX_SOURCE_DEPTH} levels deep");
+                }
+                let result = run_script(std::path::Path::new(string_arg(path)?), interpreter);
+                SOURCE_DEPTH.fetch_sub(1, Ordering::SeqCst);
+                result?
+            }
+            "reverse-history" | "outline" => {
+                for line in interpreter.outline() {
+                    println!("{line}");
+                }
+            }
+            "diff-mode" => interpreter.set_diff_mode(toggle(&args)?),
+            "compact" => interpreter.set_compact(toggle(&args)?),
+            "profile-mem" => {
+                let mut counts = NodeCounts::default();
+                counts.count(&interpreter.value());
+                println!("{counts}");
+            }
+            "schema" => {
+                let collapse_numbers = match args.as_slice() {
+                    [] => true,
+                    [Expression::Identifier(exact)] if exact == "exact" => false,
+                    _ => anyhow::bail!("usage: .schema [exact]"),
+                };
+                let value = interpreter.value();
+                with_deadline(interpreter.timeout(), || value.realize())
+                    .context("realizing value")?;
+                println!("{}", builtin::schema(&value, collapse_numbers));
+            }
+            "validate-json-schema" => {
+                let [path] = args.as_slice() else {
+                    anyhow::bail!("usage: .validate-json-schema PATH");
+                };
+                let schema =
+                    std::fs::read_to_string(string_arg(path)?).context("reading schema")?;
+                let schema: serde_json::Value =
+                    serde_json::from_str(&schema).context("parsing schema")?;
+                let result = interpreter
+                    .evaluate(Expression::FunctionCall(
+                        "json_schema_validate".to_string(),
+                        vec![
+                            Expression::This,
+                            Expression::Literal(SValue::new(Value::from(schema))),
+                        ],
+                    ))
+                    .context("validating value")?;
+                match result.as_list() {
+                    Some(errors) => {
+                        for error in errors.elements.borrow().iter() {
+                            println!("{}", error.as_string().unwrap_or_default());
+                        }
+                    }
+                    None => println!("valid"),
+                }
+            }
+            "grep" | "g" => {
+                // A plain substring, regexes aren't supported
+                let pattern = match args.as_slice() {
+                    [Expression::Identifier(word)] => word.clone(),
+                    [Expression::Literal(value)] => match &**value {
+                        Value::String(s) => s.clone(),
+                        value => value.to_string(),
+                    },
+                    _ => anyhow::bail!("usage: .grep PATTERN"),
+                };
+                if interpreter.value().as_list().is_none() {
+                    anyhow::bail!("can only grep a list");
+                }
+                // Matched against each element as it's shown, so it works for records as well
+                let matches = data::Function {
+                    name: "grep".to_string(),
+                    arities: vec![1],
+                    implementation: Box::new(move |args: Vec<SValue>| {
+                        args[0].realize()?;
+                        let shown = args[0].to_string();
+                        Ok(SValue::new(Value::Bool(shown.contains(&pattern))))
+                    }),
+                };
+                interpreter
+                    .run(Command::Expression(Expression::FunctionCall(
+                        "filter".to_string(),
+                        vec![
+                            Expression::This,
+                            Expression::Literal(SValue::new(Value::Function(matches))),
+                        ],
+                    )))
+                    .context("grepping")?
+            }
+            "count-distinct" => {
+                let field = SValue::new(Value::String(name_arg(&args)?.to_string()));
+                let count = interpreter
+                    .evaluate(Expression::FunctionCall(
+                        "distinct_count".to_string(),
+                        vec![Expression::This, Expression::Literal(field)],
+                    ))
+                    .context("counting distinct values")?;
+                println!("{count}");
+            }
+            "pin" => interpreter.pin(name_arg(&args)?.to_string()),
+            "batch-apply" => anyhow::bail!("usage: .batch-apply COMMAND"),
+            // The prompt shows the value afterwards, like after a command
+            "realize-to" | "rt" => interpreter
+                .realize_to(int_arg(&args)?)
+                .context("realizing value")?,
+            "seed-random" => builtin::set_seed(int_arg(&args)? as u64),
+            "sample-width" => interpreter.set_sample_width(int_arg(&args)?),
+            "width" => interpreter.set_terminal_width(int_arg(&args)?),
+            "let" => {
+                let [Expression::Identifier(name), expr] = args.as_slice() else {
+                    anyhow::bail!("usage: .let NAME EXPR");
+                };
+                interpreter
+                    .define(name.clone(), expr.clone())
+                    .context("defining binding")?
+            }
+            "reset-scope" => interpreter.reset_scope(),
+            "export-fn" => {
+                let [Expression::Identifier(name), path] = args.as_slice() else {
+                    anyhow::bail!("usage: .export-fn NAME PATH");
+                };
+                let Some(definition @ Expression::Lambda(..)) = interpreter.definition(name) else {
+                    anyhow::bail!("{name} is not a function defined with .let");
+                };
+                std::fs::write(string_arg(path)?, format!("{definition}\n"))
+                    .context("writing function")?;
+            }
+            "load-fn" => {
+                let [Expression::Identifier(name), path] = args.as_slice() else {
+                    anyhow::bail!("usage: .load-fn NAME PATH");
+                };
+                let source =
+                    std::fs::read_to_string(string_arg(path)?).context("reading function")?;
+                let parser::Command::Expression(expr) = parser::command(source.trim())? else {
+                    anyhow::bail!("expected a function expression");
+                };
+                interpreter
+                    .define(name.clone(), expr)
+                    .context("defining function")?
+            }
+            "restore" => interpreter
+                .restore(name_arg(&args)?)
+                .context("restoring checkpoint")?,
+            "watch-expr" => {
+                let (path, expr, interval) = match args.as_slice() {
+                    [path, expr] => (path, expr, 1000),
+                    [path, expr, interval] => {
+                        (path, expr, int_arg(std::slice::from_ref(interval))?)
+                    }
+                    _ => anyhow::bail!("usage: .watch-expr PATH EXPR [INTERVAL_MS]"),
+                };
+                watch_expr(
+                    interpreter,
+                    string_arg(path)?,
+                    expr,
+                    std::time::Duration::from_millis(interval as u64),
+                )?
+            }
+            _ => anyhow::bail!("Unknown directive `{}`", name),
         },
     }
     Ok(true)
 }
+
+/// `-`/`+` lines for the parts of the preview that changed between two values. Containers are
+/// compared element by element, other values as a whole.
+fn diff(old: &Value, new: &Value, width: usize) -> error::Result<Vec<String>> {
+    old.sample_n(width)?;
+    new.sample_n(width)?;
+    let show = |v: &Value| v.sampled(width).to_string();
+    let mut lines = vec![];
+    match (old, new) {
+        (Value::Dict(old), Value::Dict(new)) => {
+            let old = old.elements.borrow();
+            let new = new.elements.borrow();
+            for (k, v) in old.iter().take(width) {
+                match new.get(k) {
+                    Some(n) if show(n) == show(v) => {}
+                    Some(n) => {
+                        lines.push(format!("- {k}: {}", show(v)));
+                        lines.push(format!("+ {k}: {}", show(n)));
+                    }
+                    None => lines.push(format!("- {k}: {}", show(v))),
+                }
+            }
+            for (k, v) in new.iter().take(width) {
+                if !old.contains_key(k) {
+                    lines.push(format!("+ {k}: {}", show(v)));
+                }
+            }
+        }
+        (Value::List(old), Value::List(new)) => {
+            let old = old.elements.borrow();
+            let new = new.elements.borrow();
+            for i in 0..width.min(old.len().max(new.len())) {
+                match (old.get(i).map(|v| show(v)), new.get(i).map(|v| show(v))) {
+                    (o, n) if o == n => {}
+                    (o, n) => {
+                        lines.extend(o.map(|o| format!("- [{i}]: {o}")));
+                        lines.extend(n.map(|n| format!("+ [{i}]: {n}")));
+                    }
+                }
+            }
+        }
+        (old, new) if show(old) != show(new) => {
+            lines.push(format!("- {}", show(old)));
+            lines.push(format!("+ {}", show(new)));
+        }
+        _ => {}
+    }
+    Ok(lines)
+}
+
+/// How many nodes of each type are realized in a value
+#[derive(Debug, Default, PartialEq)]
+struct NodeCounts {
+    nulls: usize,
+    bools: usize,
+    ints: usize,
+    floats: usize,
+    dates: usize,
+    strings: usize,
+    lists: usize,
+    dicts: usize,
+    functions: usize,
+    /// Containers whose rest isn't realized yet
+    lazy_tails: usize,
+}
+
+impl NodeCounts {
+    /// Walks only what's realized, without forcing anything
+    fn count(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.nulls += 1,
+            Value::Bool(_) => self.bools += 1,
+            Value::Int(_) | Value::BigInt(_) => self.ints += 1,
+            Value::Float(_) => self.floats += 1,
+            Value::Date(_) => self.dates += 1,
+            Value::String(_) => self.strings += 1,
+            Value::List(l) => {
+                self.lists += 1;
+                self.lazy_tails += l.rest.borrow().is_some() as usize;
+                for e in l.elements.borrow().iter() {
+                    self.count(e);
+                }
+            }
+            Value::Dict(d) => {
+                self.dicts += 1;
+                self.lazy_tails += d.rest.borrow().is_some() as usize;
+                for e in d.elements.borrow().values() {
+                    self.count(e);
+                }
+            }
+            Value::Function(_) => self.functions += 1,
+        }
+    }
+}
+
+impl std::fmt::Display for NodeCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "nulls:      {}", self.nulls)?;
+        writeln!(f, "bools:      {}", self.bools)?;
+        writeln!(f, "ints:       {}", self.ints)?;
+        writeln!(f, "floats:     {}", self.floats)?;
+        writeln!(f, "dates:      {}", self.dates)?;
+        writeln!(f, "strings:    {}", self.strings)?;
+        writeln!(f, "lists:      {}", self.lists)?;
+        writeln!(f, "dicts:      {}", self.dicts)?;
+        writeln!(f, "functions:  {}", self.functions)?;
+        write!(f, "lazy tails: {}", self.lazy_tails)
+    }
+}
+
+/// Plain English description of what running the command would do
+fn explain(interpreter: &Interpreter, command: &Command) -> String {
+    let scope = interpreter.scope();
+    match command {
+        Command::Expression(e) => describe(&scope, &[], e),
+        Command::ShiftRight(None) => match &*interpreter.value() {
+            Value::Dict(_) => "shifts right into each entry of the current dict, binding the key \
+                               to `k` and the value to `v`"
+                .to_string(),
+            _ => "shifts right into each element of the current list".to_string(),
+        },
+        Command::ShiftRight(Some((k, v))) => format!(
+            "shifts right into each entry of the current dict, binding the key to `{k}` and the \
+             value to `{v}`"
+        ),
+        Command::ShiftLeft(None) => {
+            "shifts left, collecting the result for each element into a list".to_string()
+        }
+        Command::ShiftLeft(Some((k, v))) => format!(
+            "shifts left, collecting the results into a dict with {} as the key and {} as the value",
+            describe(&scope, &[], k),
+            describe(&scope, &[], v)
+        ),
+    }
+}
+
+/// `bound` are the names bound by the lambdas and comprehensions around the expression
+fn describe(scope: &interpreter::Scope, bound: &[&str], e: &Expression) -> String {
+    let binary = |op: &str, x: &Expression, y: &Expression| {
+        format!(
+            "{} {op} {}",
+            describe(scope, bound, x),
+            describe(scope, bound, y)
+        )
+    };
+    let call = |name: &str, args: &[String]| {
+        let args = if args.is_empty() {
+            String::new()
+        } else {
+            format!(" with {}", args.join(" and "))
+        };
+        match scope.get(name).map(|v| &**v) {
+            Some(Value::Function(f)) if f.name == "lambda" => {
+                format!("calls function `{name}`{args}")
+            }
+            Some(Value::Function(f)) => {
+                let arities: Vec<_> = f.arities.iter().map(usize::to_string).collect();
+                format!(
+                    "calls builtin `{name}` (taking {} arguments){args}",
+                    arities.join(" or ")
+                )
+            }
+            Some(_) => format!("calls `{name}`, which is not a function,{args}"),
+            None => format!("calls `{name}`, which is not defined,{args}"),
+        }
+    };
+    match e {
+        Expression::This => "the current value".to_string(),
+        Expression::Literal(v) => format!("the literal {v}"),
+        Expression::Plus(x, y) => binary("plus", x, y),
+        Expression::Minus(x, y) => binary("minus", x, y),
+        Expression::UnaryMinus(x) => format!("the negation of {}", describe(scope, bound, x)),
+        Expression::Multiply(x, y) => binary("times", x, y),
+        Expression::Divide(x, y) => binary("divided by", x, y),
+        Expression::Modulo(x, y) => binary("modulo", x, y),
+        Expression::And(x, y) => binary("and", x, y),
+        Expression::Or(x, y) => binary("or", x, y),
+        Expression::Eq(x, y) => binary("equals", x, y),
+        Expression::Ne(x, y) => binary("differs from", x, y),
+        Expression::Lt(x, y) => binary("is less than", x, y),
+        Expression::Gt(x, y) => binary("is greater than", x, y),
+        Expression::Le(x, y) => binary("is at most", x, y),
+        Expression::Ge(x, y) => binary("is at least", x, y),
+        Expression::List(l) => {
+            let elements: Vec<_> = l.iter().map(|e| describe(scope, bound, e)).collect();
+            format!("a list of [{}]", elements.join(", "))
+        }
+        Expression::Dict(d) => {
+            let entries: Vec<_> = d
+                .iter()
+                .map(|(k, e)| format!("{k:?} set to {}", describe(scope, bound, e)))
+                .collect();
+            format!("a dict with {{{}}}", entries.join(", "))
+        }
+        Expression::Identifier(name) if bound.contains(&name.as_str()) => format!("`{name}`"),
+        Expression::Identifier(name) => match scope.get(name).map(|v| &**v) {
+            Some(Value::Function(_)) => call(name, &["the current value".to_string()]),
+            Some(_) => format!("the variable `{name}`"),
+            None => format!("the undefined name `{name}`"),
+        },
+        Expression::FunctionCall(name, args) => {
+            let args: Vec<_> = args.iter().map(|e| describe(scope, bound, e)).collect();
+            call(name, &args)
+        }
+        Expression::Lambda(params, body) => {
+            let bound: Vec<_> = bound
+                .iter()
+                .copied()
+                .chain(params.iter().map(String::as_str))
+                .collect();
+            format!(
+                "a function of {} returning {}",
+                params.join(", "),
+                describe(scope, &bound, body)
+            )
+        }
+        Expression::Comprehension {
+            body,
+            variable,
+            iterable,
+            condition,
+        } => {
+            let inner: Vec<_> = bound.iter().copied().chain([variable.as_str()]).collect();
+            let condition = condition
+                .as_ref()
+                .map(|c| format!(" where {}", describe(scope, &inner, c)))
+                .unwrap_or_default();
+            format!(
+                "a lazy list of {} for each `{variable}` in {}{condition}",
+                describe(scope, &inner, body),
+                describe(scope, bound, iterable)
+            )
+        }
+    }
+}
+
+/// Run the command on a fork of the session for each pinned value
+fn batch_apply(
+    interpreter: &Interpreter,
+    command: Command,
+) -> Vec<(String, error::Result<Interpreter>)> {
+    interpreter
+        .pins()
+        .iter()
+        .map(|(name, value)| {
+            let mut forked = interpreter.fork(value.clone());
+            let result = forked.run(command.clone()).map(|()| forked);
+            (name.clone(), result)
+        })
+        .collect()
+}
+
+/// Parse the `on`/`off` argument of a toggling directive
+fn toggle(args: &[Expression]) -> Result<bool> {
+    match args {
+        [Expression::Identifier(arg)] if arg == "on" => Ok(true),
+        [Expression::Identifier(arg)] if arg == "off" => Ok(false),
+        _ => anyhow::bail!("expected `on` or `off`"),
+    }
+}
+
+/// Parse the single bare-word argument of a directive
+fn name_arg(args: &[Expression]) -> Result<&str> {
+    match args {
+        [Expression::Identifier(name)] => Ok(name),
+        _ => anyhow::bail!("expected a name"),
+    }
+}
+
+/// Parse the single integer argument of a directive
+fn int_arg(args: &[Expression]) -> Result<usize> {
+    match args {
+        [Expression::Literal(value)] => match **value {
+            Value::Int(n) if n >= 0 => Ok(n as usize),
+            _ => anyhow::bail!("expected an integer"),
+        },
+        _ => anyhow::bail!("expected an integer"),
+    }
+}
+
+/// Parse a string literal argument of a directive
+fn string_arg(arg: &Expression) -> Result<&str> {
+    match arg {
+        Expression::Literal(value) => value.as_string().context("expected a string"),
+        _ => anyhow::bail!("expected a string"),
+    }
+}
+
+/// How many `.source`s are running inside each other, to stop ones that include themselves
+static SOURCE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+const MAX_SOURCE_DEPTH: usize = 16;
+
+/// Set by Ctrl-C while a watching loop runs, which stops on it
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Whether a watching loop is running. Otherwise Ctrl-C interrupts the running command, see
+/// [`data::interrupt`].
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// Re-read the file and re-run the whole history on it every tick, showing the expression's value
+/// on a single line until Ctrl-C
+fn watch_expr(
+    interpreter: &Interpreter,
+    path: &str,
+    expr: &Expression,
+    interval: std::time::Duration,
+) -> Result<()> {
+    let _watching = handle_interrupts()?;
+    let mut out = stdout();
+    while !INTERRUPTED.swap(false, Ordering::SeqCst) {
+        let line = match std::fs::read_to_string(path) {
+            Ok(text) => interpreter
+                .replay_on(SValue::new(Value::String(text)))
+                .and_then(|replayed| replayed.evaluate(expr.clone()))
+                .and_then(|value| gauge_line(&value))
+                .unwrap_or_else(|err| format!("\rError: {err}\x1b[K")),
+            Err(err) => format!("\rError: reading {path}: {err}\x1b[K"),
+        };
+        write!(out, "{line}")?;
+        out.flush()?;
+        std::thread::sleep(interval);
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Make Ctrl-C stop the watching loop or the running command instead of exiting. Installing can
+/// only be tried once, later calls report how that went.
+fn install_interrupt_handler() -> Result<()> {
+    static HANDLER: OnceLock<std::result::Result<(), String>> = OnceLock::new();
+    HANDLER
+        .get_or_init(|| {
+            ctrlc::set_handler(|| {
+                if WATCHING.load(Ordering::SeqCst) {
+                    INTERRUPTED.store(true, Ordering::SeqCst);
+                } else {
+                    data::interrupt();
+                }
+            })
+            .map_err(|err| err.to_string())
+        })
+        .clone()
+        .map_err(anyhow::Error::msg)
+        .context("installing Ctrl-C handler")
+}
+
+/// Make Ctrl-C set [`INTERRUPTED`] for a watching loop that's about to start, until the returned
+/// guard is dropped. A Ctrl-C from before the loop is forgotten.
+fn handle_interrupts() -> Result<Watching> {
+    install_interrupt_handler()?;
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    WATCHING.store(true, Ordering::SeqCst);
+    Ok(Watching)
+}
+
+/// Makes Ctrl-C interrupt commands again when the watching loop is over, see [`handle_interrupts`]
+struct Watching;
+
+impl Drop for Watching {
+    fn drop(&mut self) {
+        WATCHING.store(false, Ordering::SeqCst);
+    }
+}
+
+const FOLLOW_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Run the commands on each record of the NDJSON file, starting with the ones already in it, then
+/// polling for appended ones until Ctrl-C. A file that shrinks is read again from the start.
+fn follow_ndjson(interpreter: &Interpreter, path: &str, commands: &[String]) -> Result<()> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("opening {path}"))?;
+    let _watching = handle_interrupts()?;
+    let mut lines = LineBuffer::default();
+    let mut chunk = vec![];
+    while !INTERRUPTED.swap(false, Ordering::SeqCst) {
+        if file.metadata()?.len() < file.stream_position()? {
+            file.seek(SeekFrom::Start(0))?;
+            lines = LineBuffer::default();
+        }
+        chunk.clear();
+        file.read_to_end(&mut chunk)
+            .with_context(|| format!("reading {path}"))?;
+        if chunk.is_empty() {
+            std::thread::sleep(FOLLOW_INTERVAL);
+            continue;
+        }
+        for line in lines.push(&chunk) {
+            if let Err(err) = follow_record(interpreter, line, commands) {
+                eprintln!("{}", render_error(err));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the commands on a single record in a fork of the session and print the result
+fn follow_record(interpreter: &Interpreter, line: String, commands: &[String]) -> Result<()> {
+    let mut record = interpreter.fork(SValue::new(Value::String(line)));
+    record
+        .run(parser::command("json")?)
+        .context("parsing record")?;
+    for command in commands {
+        run(command.clone(), &mut record).with_context(|| format!("running `{command}`"))?;
+    }
+    run(".done".into(), &mut record)?;
+    Ok(())
+}
+
+/// Splits text arriving in chunks into lines, keeping a trailing partial line until the rest of
+/// it arrives
+#[derive(Default)]
+struct LineBuffer {
+    partial: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// The non-blank lines the chunk completes
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.partial.extend_from_slice(chunk);
+        let Some(end) = self.partial.iter().rposition(|&b| b == b'\n') else {
+            return vec![];
+        };
+        // Split on bytes, a chunk can end in the middle of a character
+        let complete: Vec<u8> = self.partial.drain(..=end).collect();
+        String::from_utf8_lossy(&complete)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// A single update of the gauge, overwriting the previous one
+fn gauge_line(value: &Value) -> error::Result<String> {
+    let n = value
+        .as_number()
+        .ok_or(error::Error::InvalidType("number"))?;
+    Ok(format!("\r{n}\x1b[K"))
+}
+
+/// Print each element of a list as a line of JSON as soon as it is realized
+fn json_stream(val: SValue, out: &mut impl Write) -> Result<()> {
+    if val.as_list().is_none() {
+        anyhow::bail!("expected a list, got {val}");
+    }
+    for elem in List::into_iter(val) {
+        let elem = serde_json::Value::try_from(&*elem?)?;
+        writeln!(out, "{elem}")?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use data::BoxedRest;
+
+    #[test]
+    fn test_quote() {
+        let mut interpreter = Interpreter::new("".into());
+        assert!(run(".q [not, json".into(), &mut interpreter).unwrap());
+        assert_eq!(
+            &*interpreter.value(),
+            &Value::String("[not, json".to_string())
+        );
+    }
+
+    #[test]
+    fn test_export_fn() {
+        let path = std::env::temp_dir().join(format!("pi-export-fn-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut interpreter = Interpreter::new("".into());
+        run(".let inc |x| x + 1".into(), &mut interpreter).unwrap();
+        run(format!(".export-fn inc \"{path}\""), &mut interpreter).unwrap();
+
+        let mut interpreter = Interpreter::new("".into());
+        run(format!(".load-fn plus_one \"{path}\""), &mut interpreter).unwrap();
+        run("plus_one 41".into(), &mut interpreter).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(&*interpreter.value(), &Value::Int(42));
+    }
+
+    #[test]
+    fn test_grep() {
+        let mut interpreter = Interpreter::new(
+            r#"[{"name": "alice", "city": "Paris"}, {"name": "bob", "city": "Oslo"}, "Paris"]"#
+                .into(),
+        );
+        run("json".into(), &mut interpreter).unwrap();
+        run(r#".grep "Paris""#.into(), &mut interpreter).unwrap();
+        let value = interpreter.value();
+        value.realize().unwrap();
+        assert_eq!(
+            value.to_string(),
+            r#"[{name: "alice", city: "Paris"}, "Paris"]"#
+        );
+
+        run(r#".g "alice""#.into(), &mut interpreter).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(
+            interpreter.value().to_string(),
+            r#"[{name: "alice", city: "Paris"}]"#
+        );
+
+        // Bare words work too, and keys are matched the way they're shown, without quotes
+        run(".undo".into(), &mut interpreter).unwrap();
+        run(".undo".into(), &mut interpreter).unwrap();
+        run(".grep Oslo".into(), &mut interpreter).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(
+            interpreter.value().to_string(),
+            r#"[{name: "bob", city: "Oslo"}]"#
+        );
+        run(".undo".into(), &mut interpreter).unwrap();
+        run(r#".grep "name: \"bob""#.into(), &mut interpreter).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(
+            interpreter.value().to_string(),
+            r#"[{name: "bob", city: "Oslo"}]"#
+        );
+
+        run(".undo".into(), &mut interpreter).unwrap();
+        run(r#".g "nobody""#.into(), &mut interpreter).unwrap();
+        interpreter.value().realize().unwrap();
+        assert_eq!(interpreter.value().to_string(), "[]");
+
+        run(".undo".into(), &mut interpreter).unwrap();
+        run("get % 0".into(), &mut interpreter).unwrap();
+        assert!(run(r#".grep "x""#.into(), &mut interpreter).is_err());
+    }
+
+    #[test]
+    fn test_reset_scope() {
+        let mut interpreter = Interpreter::new("[1]".into());
+        run(".let x 42".into(), &mut interpreter).unwrap();
+        run(".reset-scope".into(), &mut interpreter).unwrap();
+        assert!(run("x".into(), &mut interpreter).is_err());
+        run("json".into(), &mut interpreter).unwrap();
+        assert_eq!(interpreter.value().to_string(), "[1]");
+    }
+
+    #[test]
+    fn test_source() {
+        let path = std::env::temp_dir().join(format!("pi-source-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            format!("# keeps including itself\nget % 0\n.source \"{path}\"\n"),
+        )
+        .unwrap();
+
+        let nested = format!("{}1{}", "[".repeat(20), "]".repeat(20));
+        let mut interpreter = Interpreter::new(nested);
+        run("json".into(), &mut interpreter).unwrap();
+        let err = run(format!(".include \"{path}\""), &mut interpreter).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        // Stopped after 16 levels, with the line that failed in each of them
+        assert_eq!(interpreter.value().to_string(), "[[[[1]]]]");
+        let rendered = render_error(err);
+        assert_eq!(rendered.matches(&format!("line 3 of {path}")).count(), 16);
+        assert!(rendered.ends_with("scripts sourced more than 16 levels deep"));
+
+        // The lines after a failing one aren't run
+        std::fs::write(path, "get % 0\nnot valid (\nget % 0\n").unwrap();
+        let mut interpreter = Interpreter::new("[[[1]]]".into());
+        run("json".into(), &mut interpreter).unwrap();
+        let err = run(format!(".source \"{path}\""), &mut interpreter).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(interpreter.value().to_string(), "[[1]]");
+        assert!(render_error(err).contains(&format!("line 2 of {path}")));
+    }
+
+    #[test]
+    fn test_diff_mode() {
+        let mut interpreter = Interpreter::new(r#"{"a": 1, "b": [1, 2]}"#.into());
+        run("json".into(), &mut interpreter).unwrap();
+        run(".diff-mode on".into(), &mut interpreter).unwrap();
+        assert!(interpreter.diff_mode());
+
+        // What the prompt shows after each line
+        let shown = |line: &str, interpreter: &mut Interpreter| {
+            let (mut out, mut err) = (vec![], vec![]);
+            assert!(interact(line.into(), interpreter, &mut out, &mut err).unwrap());
+            (
+                String::from_utf8(out).unwrap(),
+                String::from_utf8(err).unwrap(),
+            )
+        };
+        let (out, err) = shown(r#"assoc % "c" 3"#, &mut interpreter);
+        assert_eq!(out, "\n+ c: 3\n{a: 1, b: [1, 2], c: 3}\n$> ");
+        assert!(err.is_empty());
+
+        // A line that fails has no diff, even if it changed the value before failing
+        let path = std::env::temp_dir().join(format!("pi-diff-mode-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "dissoc % \"c\"\nnot valid (\n").unwrap();
+        let (out, err) = shown(&format!(".source \"{path}\""), &mut interpreter);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(out, "\n{a: 1, b: [1, 2]}\n$> ");
+        assert!(err.starts_with("Error: "));
+        run(".undo".into(), &mut interpreter).unwrap();
+
+        let previous = interpreter.value();
+        run(r#"get % "b""#.into(), &mut interpreter).unwrap();
+        assert_eq!(
+            diff(&previous, &interpreter.value(), 3).unwrap(),
+            vec!["- {a: 1, b: [1, 2], c: 3}", "+ [1, 2]"]
+        );
+
+        run("[x * x for x in %]".into(), &mut interpreter).unwrap();
+        let previous = interpreter.value();
+        run(".undo".into(), &mut interpreter).unwrap();
+        assert_eq!(
+            diff(&previous, &interpreter.value(), 3).unwrap(),
+            vec!["- [1]: 4", "+ [1]: 2"]
+        );
+        assert!(diff(&previous, &previous, 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_node_counts() {
+        let mut interpreter = Interpreter::new(
+            r#"{"a": [1, 2.5, "x"], "b": {"c": null, "d": true}, "e": "y"}"#.into(),
+        );
+        run("json".into(), &mut interpreter).unwrap();
+        interpreter.value().realize().unwrap();
+        run(r#"assoc % "f" (cycle [1])"#.into(), &mut interpreter).unwrap();
+
+        let mut counts = NodeCounts::default();
+        counts.count(&interpreter.value());
+        assert_eq!(
+            counts,
+            NodeCounts {
+                nulls: 1,
+                bools: 1,
+                ints: 1,
+                floats: 1,
+                dates: 0,
+                strings: 2,
+                lists: 2,
+                dicts: 2,
+                functions: 0,
+                lazy_tails: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let interpreter = Interpreter::new("".into());
+        let explained = |source| explain(&interpreter, &parser::command(source).unwrap());
+        assert_eq!(
+            explained(r#"get % "a""#),
+            r#"calls builtin `get` (taking 2 arguments) with the current value and the literal "a""#
+        );
+        assert_eq!(
+            explained("[x * 2 for x in % if x > 1]"),
+            "a lazy list of `x` times the literal 2 for each `x` in the current value \
+             where `x` is greater than the literal 1"
+        );
+        assert_eq!(
+            explained("|r| get r 0"),
+            "a function of r returning calls builtin `get` (taking 2 arguments) with `r` and the \
+             literal 0"
+        );
+        assert_eq!(
+            explained(">>"),
+            "shifts right into each element of the current list"
+        );
+    }
+
+    #[test]
+    fn test_batch_apply() {
+        let mut interpreter = Interpreter::new(r#"{"a": [1, 2], "b": [3]}"#.into());
+        run("json".into(), &mut interpreter).unwrap();
+        run(r#"get % "a""#.into(), &mut interpreter).unwrap();
+        run(".pin first".into(), &mut interpreter).unwrap();
+        run(".undo".into(), &mut interpreter).unwrap();
+        run(r#"get % "b""#.into(), &mut interpreter).unwrap();
+        run(".pin second".into(), &mut interpreter).unwrap();
+        run(".undo".into(), &mut interpreter).unwrap();
+
+        let command = parser::command("[x * 10 for x in %]").unwrap();
+        let results: Vec<_> = batch_apply(&interpreter, command)
+            .into_iter()
+            .map(|(name, forked)| {
+                let value = forked.unwrap().value();
+                value.realize().unwrap();
+                (name, value.to_string())
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("first".to_string(), "[10, 20]".to_string()),
+                ("second".to_string(), "[30]".to_string()),
+            ]
+        );
+        // The session itself is left alone
+        assert_eq!(
+            interpreter
+                .value()
+                .as_dict()
+                .unwrap()
+                .elements
+                .borrow()
+                .len(),
+            2
+        );
+
+        // The directive takes the rest of the line as the command
+        run(".batch-apply [x * 10 for x in %]".into(), &mut interpreter).unwrap();
+        run(".batch-apply get % 0".into(), &mut interpreter).unwrap();
+        assert!(run(".batch-apply".into(), &mut interpreter).is_err());
+    }
+
+    #[test]
+    fn test_realize_to() {
+        let mut interpreter = Interpreter::new("[1, 2]".into());
+        run("json".into(), &mut interpreter).unwrap();
+        run("cycle".into(), &mut interpreter).unwrap();
+        run(".rt 5".into(), &mut interpreter).unwrap();
+        let value = interpreter.value();
+        let list = value.as_list().unwrap();
+        assert_eq!(list.elements.borrow().len(), 5);
+        assert!(list.rest.borrow().is_some());
+    }
+
+    #[test]
+    fn test_render_value() {
+        let mut interpreter = Interpreter::new("[[1, 2, 3, 4], 2, 3, 4, 5, 6]".into());
+        run("json".into(), &mut interpreter).unwrap();
+        assert_eq!(
+            render_value(&interpreter).unwrap(),
+            "[[1, 2, 3, ...], 2, 3, ...]"
+        );
+        run(".sample-width 5".into(), &mut interpreter).unwrap();
+        assert_eq!(
+            render_value(&interpreter).unwrap(),
+            "[[1, 2, 3, 4], 2, 3, 4, 5, ...]"
+        );
+    }
+
+    #[test]
+    fn test_compact_line() {
+        let mut interpreter = Interpreter::new(r#"[{"a": "x\ny"}, {"a": 2}]"#.into());
+        run("json".into(), &mut interpreter).unwrap();
+        run(".width 80".into(), &mut interpreter).unwrap();
+        run(".compact on".into(), &mut interpreter).unwrap();
+        assert!(interpreter.compact());
+        assert_eq!(
+            compact_line(&interpreter).unwrap(),
+            r#"[{a: "x\ny"}, {a: 2}]"#
+        );
+
+        run(">>".into(), &mut interpreter).unwrap();
+        let line = compact_line(&interpreter).unwrap();
+        assert_eq!(line, r#"[list ()] {a: "x\ny"}"#);
+        assert_eq!(line.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_terminal_width() {
+        let mut interpreter = Interpreter::new("[1000, 2000, 3000]".into());
+        run("json".into(), &mut interpreter).unwrap();
+        run(".width 80".into(), &mut interpreter).unwrap();
+        assert_eq!(render_value(&interpreter).unwrap(), "[1000, 2000, 3000]");
+        run(".width 12".into(), &mut interpreter).unwrap();
+        assert_eq!(render_value(&interpreter).unwrap(), "[1000, 20...");
+        run(".width 18".into(), &mut interpreter).unwrap();
+        assert_eq!(render_value(&interpreter).unwrap(), "[1000, 2000, 3000]");
+    }
+
+    #[test]
+    fn test_error_mode() {
+        let failing = || {
+            SValue::new(Value::List(List {
+                elements: vec![SValue::new(Value::Int(1))].into(),
+                rest: Some(Box::new(
+                    vec![
+                        Err(error::Error::BuiltinFunctionError("bad element".into())),
+                        Ok(SValue::new(Value::Int(3))),
+                    ]
+                    .into_iter(),
+                ) as BoxedRest<_>)
+                .into(),
+            }))
+        };
+
+        let mut interpreter = Interpreter::new("".into());
+        interpreter.set_sample_width(5);
+        interpreter
+            .run(parser::Command::Expression(Expression::Literal(failing())))
+            .unwrap();
+        assert_eq!(
+            render_value(&interpreter).unwrap(),
+            "[1, <error: bad element>, ...]"
+        );
+        // Rendering leaves the failing element in the list
+        assert!(interpreter.run(parser::command("len").unwrap()).is_err());
+        assert_eq!(
+            render_value(&interpreter).unwrap(),
+            "[1, <error: bad element>, ...]"
+        );
+
+        interpreter
+            .run(parser::Command::Expression(Expression::Literal(failing())))
+            .unwrap();
+        run(".errmode strict".into(), &mut interpreter).unwrap();
+        assert!(render_value(&interpreter).is_err());
+    }
+
+    #[test]
+    fn test_line_buffer() {
+        let mut lines = LineBuffer::default();
+        assert_eq!(lines.push(b"{\"a\": 1}\n{\"a\""), vec![r#"{"a": 1}"#]);
+        assert!(lines.push(b": 2").is_empty());
+        assert_eq!(lines.push(b"}\r\n\n"), vec![r#"{"a": 2}"#]);
+
+        // A character split across chunks
+        let snowman = "\"\u{2603}\"\n".as_bytes();
+        assert!(lines.push(&snowman[..2]).is_empty());
+        assert_eq!(lines.push(&snowman[2..]), vec!["\"\u{2603}\""]);
+
+        let interpreter = Interpreter::new("".into());
+        let record = lines.push(b"{\"level\": \"warn\"}\n").remove(0);
+        assert!(follow_record(&interpreter, record, &[r#"get % "level""#.into()]).is_ok());
+        assert!(follow_record(&interpreter, "{".into(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_json_stream() {
+        let list = SValue::new(Value::List(List {
+            elements: vec![SValue::new(Value::Int(1))].into(),
+            rest: Some(Box::new(
+                vec![
+                    Ok(SValue::new(Value::String("two".into()))),
+                    Err(error::Error::BuiltinFunctionError("bad element".into())),
+                    Ok(SValue::new(Value::Null)),
+                ]
+                .into_iter(),
+            ) as BoxedRest<_>)
+            .into(),
+        }));
+        let mut out = vec![];
+        assert!(json_stream(list, &mut out).is_err());
+        assert_eq!(String::from_utf8(out).unwrap(), "1\n\"two\"\n");
+    }
+
+    #[test]
+    fn test_save_output() {
+        let path = std::env::temp_dir().join(format!("pi-save-output-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut interpreter = Interpreter::new(r#"[{"a": 1}, [2], "three"]"#.into());
+        run("json".into(), &mut interpreter).unwrap();
+        run(format!(".save-output \"{path}\""), &mut interpreter).unwrap();
+        run(
+            format!(".save-output \"{path}\" --append"),
+            &mut interpreter,
+        )
+        .unwrap();
+        let saved = std::fs::read_to_string(path).unwrap();
+        assert_eq!(saved.lines().count(), 6);
+        assert_eq!(saved.lines().next(), Some(r#"{"a":1}"#));
+
+        run(format!(".save-output \"{path}\""), &mut interpreter).unwrap();
+        let saved = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(saved.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_render_error() {
+        assert_eq!(
+            render_error(error::Error::FunctionNotFound("nope".into())),
+            "Error: Function nope not found"
+        );
+
+        let mut interpreter = Interpreter::new("".into());
+        let err = run("nope 1".into(), &mut interpreter).unwrap_err();
+        assert_eq!(
+            render_error(err),
+            "Error: running command\n  caused by: Function nope not found"
+        );
+
+        let err = seed(Some("/nonexistent/pi-input.json"), None).unwrap_err();
+        let rendered = render_error(err);
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(lines[0], "Error: reading /nonexistent/pi-input.json");
+        assert!(lines[1].starts_with("  caused by: "));
+    }
+
+    #[test]
+    fn test_gauge_line() {
+        assert_eq!(gauge_line(&Value::Int(42)).unwrap(), "\r42\x1b[K");
+        assert_eq!(gauge_line(&Value::Float(1.5)).unwrap(), "\r1.5\x1b[K");
+        assert!(matches!(
+            gauge_line(&Value::String("x".into())),
+            Err(error::Error::InvalidType("number"))
+        ));
+    }
+}
@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 use crate::data::{SValue, Value};
 
 peg::parser! {
@@ -8,54 +6,156 @@ peg::parser! {
     rule _()
       = [' ' | '\n' | '\t']+
 
+    rule ident_char()
+      = ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']
+
+    rule keyword()
+      = ("and" / "or" / "for" / "in" / "if" / "null" / "true" / "false") !ident_char()
+
     rule ident()
-        = quiet!{[ 'a'..='z' | 'A'..='Z']['a'..='z' | 'A'..='Z' | '0'..='9' ]*}
+        = quiet!{!keyword() [ 'a'..='z' | 'A'..='Z' | '_'] ident_char()*}
         / expected!("identifier")
 
     rule parens() -> Expression
         = "(" _? e:expression() _? ")" { e }
 
-    rule number() -> u64
-      = n:$(['0'..='9']+) {? n.parse().or(Err("u32")) }
+    /// Negative numbers are parsed as a unary minus. Ones too big for `Int` are kept as digits,
+    /// like in JSON input.
+    rule number() -> Value
+      = n:$(['0'..='9']+) {
+          n.parse().map(Value::Int).unwrap_or_else(|_| {
+              Value::BigInt(n.trim_start_matches('0').to_string())
+          })
+      }
 
     rule string() -> String
-      = "\"" s:$([^ '"']*) "\"" { s.to_string() }
+      = "\"" s:string_char()* "\"" { s.into_iter().collect() }
+
+    rule string_char() -> char
+      = c:[^ '"' | '\\'] { c }
+      / "\\" e:escape() { e }
+
+    /// JSON escapes, plus the `\0` and `\u{...}` that displaying a string can produce
+    rule escape() -> char
+      = "\"" { '"' }
+      / "\\" { '\\' }
+      / "/" { '/' }
+      / "n" { '\n' }
+      / "t" { '\t' }
+      / "r" { '\r' }
+      / "b" { '\u{8}' }
+      / "f" { '\u{c}' }
+      / "0" { '\0' }
+      / "u" high:hex4() "\\u" low:hex4() {?
+          if (0xD800..0xDC00).contains(&high) && (0xDC00..0xE000).contains(&low) {
+              char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+                  .ok_or("surrogate pair")
+          } else {
+              Err("surrogate pair")
+          }
+      }
+      / "u" n:hex4() {? char::from_u32(n).ok_or("unicode scalar value") }
+      / "u{" n:$(['0'..='9' | 'a'..='f' | 'A'..='F']*<1,6>) "}" {?
+          u32::from_str_radix(n, 16)
+              .ok()
+              .and_then(char::from_u32)
+              .ok_or("unicode scalar value")
+      }
+
+    rule hex4() -> u32
+      = n:$(['0'..='9' | 'a'..='f' | 'A'..='F']*<4>) {?
+          u32::from_str_radix(n, 16).or(Err("hex digits"))
+      }
+
+    rule float() -> f64
+      = n:$(['0'..='9']* "." ['0'..='9']+ / ['0'..='9']+ "." !['0'..='9']) {?
+          n.parse().or(Err("f64"))
+      }
 
     rule literal() -> Value
-        // TODO: float, null, bool
-      = n:number() { Value::Int(n) }
+      = "null" !ident_char() { Value::Null }
+      / "true" !ident_char() { Value::Bool(true) }
+      / "false" !ident_char() { Value::Bool(false) }
+      / f:float() { Value::Float(f) }
+      / n:number() { n }
       / s:string() { Value::String(s.to_string()) }
 
     rule list() -> Vec<Expression>
       = "[" _? v:expression() ** (_? "," _?) _? "]" { v }
 
+    /// A bare key is the name itself, like in javascript, not the value of a variable
     rule _pair() -> (String, Expression)
-      = k:string() _? ":" _? v:expression() { (k, v) }
+      = k:(string() / k:$(ident()) { k.to_string() }) _? ":" _? v:expression() { (k, v) }
 
-    rule dict() -> HashMap<String, Expression>
-      = "{" _? pairs:(_pair() ** (_? "," _?)) _? "}" { pairs.into_iter().collect() }
+    rule dict() -> Vec<(String, Expression)>
+      = "{" _? pairs:(_pair() ** (_? "," _?)) _? "}" { pairs }
 
     rule function_call() -> (String, Vec<Expression>)
-      = f:$(ident()) args:(_ a:expression() ** _ {a})? { (f.to_string(), args.unwrap_or_default()) }
+      = f:$(ident()) args:(_ a:argument() ** _ {a})? { (f.to_string(), args.unwrap_or_default()) }
+
+    /// Arguments don't extend to function calls without parentheses, so `get x 1` passes `x` as
+    /// a value instead of calling it. This breaks inputs written when arguments were whole
+    /// expressions: `get % get [0] 0` is now `get` called with four arguments and has to be
+    /// written `get % (get [0] 0)`, and `get % 0 + 1` is `(get % 0) + 1`.
+    rule argument() -> Expression
+      = "%" !"%" { Expression::This }
+      / l:lambda() { Expression::Lambda(l.0, Box::new(l.1)) }
+      / l:literal() { Expression::Literal(SValue::new(l)) }
+      / l:list() { Expression::List(l) }
+      / d:dict() { Expression::Dict(d) }
+      / p:parens() { p }
+      / i:$(ident()) { Expression::Identifier(i.into()) }
+
+    rule lambda() -> (Vec<String>, Expression)
+      = "|" _? params:($(ident()) ** (_? "," _?)) _? "|" _? body:expression() {
+          (params.into_iter().map(String::from).collect(), body)
+      }
+
+    rule comprehension() -> Expression
+      = "[" _? body:expression() _ "for" _ variable:$(ident()) _ "in" _ iterable:expression()
+        condition:(_ "if" _ c:expression() {c})? _? "]" {
+          Expression::Comprehension {
+              body: Box::new(body),
+              variable: variable.to_string(),
+              iterable: Box::new(iterable),
+              condition: condition.map(Box::new),
+          }
+      }
 
     rule atom() -> Expression
-      = "%" { Expression::This }
+      = "%" !"%" { Expression::This }
+      / l:lambda() { Expression::Lambda(l.0, Box::new(l.1)) }
       / l:literal() { Expression::Literal(SValue::new(l)) }
+      / c:comprehension() { c }
       / l:list() { Expression::List(l) }
       / d:dict() { Expression::Dict(d) }
-      / i:$(ident()) !_ { Expression::Identifier(i.into()) }
+      / i:$(ident()) !(_ argument()) { Expression::Identifier(i.into()) }
       / f:function_call() { Expression::FunctionCall(f.0, f.1) }
 
+    /// From loosest to tightest: `or`, `and`, comparisons, `+ -`, `* /`, unary `-`, then atoms. Binary
+    /// operators are left associative. A function call's arguments are atoms, so
+    /// `get % 0 + 1` is `(get % 0) + 1`.
     rule expression() -> Expression = precedence!{
+        x:(@) _ "or" _ y:@ { Expression::Or(Box::new(x), Box::new(y)) }
+        --
+        x:(@) _ "and" _ y:@ { Expression::And(Box::new(x), Box::new(y)) }
+        --
+        x:(@) _? "==" _? y:@ { Expression::Eq(Box::new(x), Box::new(y)) }
+        x:(@) _? "!=" _? y:@ { Expression::Ne(Box::new(x), Box::new(y)) }
+        x:(@) _? "<=" _? y:@ { Expression::Le(Box::new(x), Box::new(y)) }
+        x:(@) _? ">=" _? y:@ { Expression::Ge(Box::new(x), Box::new(y)) }
+        x:(@) _? "<" _? y:@ { Expression::Lt(Box::new(x), Box::new(y)) }
+        x:(@) _? ">" _? y:@ { Expression::Gt(Box::new(x), Box::new(y)) }
+        --
         x:(@) _? "+" _? y:@ { Expression::Plus(Box::new(x), Box::new(y)) }
         x:(@) _? "-" _? y:@ { Expression::Minus(Box::new(x), Box::new(y)) }
-              "-" _? v:@ { Expression::UnaryMinus(Box::new(v)) }
         --
         x:(@) _? "*" _? y:@ { Expression::Multiply(Box::new(x), Box::new(y)) }
         x:(@) _? "/" _? y:@ { Expression::Divide(Box::new(x), Box::new(y)) }
+        x:(@) _? "%%" _? y:@ { Expression::Modulo(Box::new(x), Box::new(y)) }
         --
-        x:(@) _ "and" _ y:@ { Expression::And(Box::new(x), Box::new(y)) }
-        x:(@) _ "or" _ y:@ { Expression::Or(Box::new(x), Box::new(y)) }
+        // Tighter than the binary operators, so `1 - -2` and `-2 * 3` work
+        "-" _? v:@ { Expression::UnaryMinus(Box::new(v)) }
         --
         "(" _? v:expression() _? ")" { v }
         n:atom() {n}
@@ -70,8 +170,24 @@ peg::parser! {
             Command::ShiftLeft(kv)
         }
 
+    rule directive() -> (String, Vec<Expression>)
+      = f:$(ident() ++ "-") args:(_ a:directive_argument() ** _ {a})? { (f.to_string(), args.unwrap_or_default()) }
+
+    /// Flags like `--append` are kept as identifiers, dashes included
+    rule directive_argument() -> Expression
+      = f:$("--" ident() ++ "-") { Expression::Identifier(f.into()) }
+      / argument()
+
     pub rule user_input() -> UserInput
-        = "." f:function_call() { UserInput::Directive(f.0, f.1) }
+        // The rest of the line is taken verbatim, `.q` without text is still `quit`
+        = "." ("quote" / "q") " " text:$([_]*) {
+            UserInput::Command(Command::Expression(Expression::Literal(SValue::new(
+                Value::String(text.to_string())
+            ))))
+        }
+        / "." "explain" _ c:command() { UserInput::Explain(c) }
+        / "." "batch-apply" _ c:command() { UserInput::BatchApply(c) }
+        / "." d:directive() { UserInput::Directive(d.0, d.1) }
         / c:command() { UserInput::Command(c) }
   }
 }
@@ -87,14 +203,111 @@ pub enum Expression {
     UnaryMinus(Box<Expression>),
     Multiply(Box<Expression>, Box<Expression>),
     Divide(Box<Expression>, Box<Expression>),
+    /// `%%`, since `%` is the current value
+    Modulo(Box<Expression>, Box<Expression>),
     And(Box<Expression>, Box<Expression>),
     Or(Box<Expression>, Box<Expression>),
+    Eq(Box<Expression>, Box<Expression>),
+    Ne(Box<Expression>, Box<Expression>),
+    Lt(Box<Expression>, Box<Expression>),
+    Gt(Box<Expression>, Box<Expression>),
+    Le(Box<Expression>, Box<Expression>),
+    Ge(Box<Expression>, Box<Expression>),
 
     List(Vec<Expression>),
-    Dict(HashMap<String, Expression>),
+    /// Entries in source order. A repeated key keeps its first position and its last value.
+    Dict(Vec<(String, Expression)>),
 
     Identifier(String),
     FunctionCall(String, Vec<Expression>),
+    /// Parameter names and the body, evaluated with the parameters bound in scope
+    Lambda(Vec<String>, Box<Expression>),
+    /// `[body for variable in iterable if condition]`, a lazy map and filter
+    Comprehension {
+        body: Box<Expression>,
+        variable: String,
+        iterable: Box<Expression>,
+        condition: Option<Box<Expression>>,
+    },
+}
+
+/// Source form of the expression, which parses back into an equivalent expression
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::This => write!(f, "%"),
+            // `2.0` would be shown as `2`, which parses back as an integer
+            Expression::Literal(v) => match **v {
+                Value::Float(n) => write!(f, "{:?}", n),
+                _ => write!(f, "{}", v),
+            },
+            Expression::Plus(x, y) => write!(f, "({} + {})", x, y),
+            Expression::Minus(x, y) => write!(f, "({} - {})", x, y),
+            Expression::UnaryMinus(x) => write!(f, "(-{})", x),
+            Expression::Multiply(x, y) => write!(f, "({} * {})", x, y),
+            Expression::Divide(x, y) => write!(f, "({} / {})", x, y),
+            Expression::Modulo(x, y) => write!(f, "({} %% {})", x, y),
+            Expression::And(x, y) => write!(f, "({} and {})", x, y),
+            Expression::Or(x, y) => write!(f, "({} or {})", x, y),
+            Expression::Eq(x, y) => write!(f, "({} == {})", x, y),
+            Expression::Ne(x, y) => write!(f, "({} != {})", x, y),
+            Expression::Lt(x, y) => write!(f, "({} < {})", x, y),
+            Expression::Gt(x, y) => write!(f, "({} > {})", x, y),
+            Expression::Le(x, y) => write!(f, "({} <= {})", x, y),
+            Expression::Ge(x, y) => write!(f, "({} >= {})", x, y),
+            Expression::List(l) => {
+                write!(f, "[")?;
+                for (i, e) in l.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                write!(f, "]")
+            }
+            Expression::Dict(d) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in d.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: {}", k, v)?;
+                }
+                write!(f, "}}")
+            }
+            Expression::Identifier(name) => write!(f, "{}", name),
+            Expression::FunctionCall(name, args) => {
+                if args.is_empty() {
+                    return write!(f, "{}", name);
+                }
+                write!(f, "({}", name)?;
+                for arg in args {
+                    match arg {
+                        Expression::This
+                        | Expression::Literal(_)
+                        | Expression::List(_)
+                        | Expression::Dict(_)
+                        | Expression::Identifier(_) => write!(f, " {}", arg)?,
+                        _ => write!(f, " ({})", arg)?,
+                    }
+                }
+                write!(f, ")")
+            }
+            Expression::Lambda(params, body) => write!(f, "|{}| {}", params.join(", "), body),
+            Expression::Comprehension {
+                body,
+                variable,
+                iterable,
+                condition,
+            } => {
+                write!(f, "[{} for {} in {}", body, variable, iterable)?;
+                if let Some(condition) = condition {
+                    write!(f, " if {}", condition)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -106,10 +319,26 @@ pub enum Command {
     Expression(Expression),
 }
 
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::ShiftRight(None) => write!(f, ">>"),
+            Command::ShiftRight(Some((k, v))) => write!(f, ">> {}:{}", k, v),
+            Command::ShiftLeft(None) => write!(f, "<<"),
+            Command::ShiftLeft(Some((k, v))) => write!(f, "<< {}: {}", k, v),
+            Command::Expression(e) => write!(f, "{}", e),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum UserInput {
     Command(Command),
     Directive(String, Vec<Expression>),
+    /// `.explain COMMAND`, describe the command instead of running it
+    Explain(Command),
+    /// `.batch-apply COMMAND`, run the command on each pinned value
+    BatchApply(Command),
 }
 
 #[cfg(test)]
@@ -117,6 +346,7 @@ mod test {
     use super::*;
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_basic() {
         assert_eq!(
             pi_parser::command("123"),
@@ -125,6 +355,41 @@ mod test {
             ))))
         );
 
+        assert_eq!(
+            pi_parser::command("3.14"),
+            Ok(Command::Expression(Expression::Literal(SValue::new(
+                Value::Float(3.14)
+            ))))
+        );
+
+        assert_eq!(
+            pi_parser::command("0.5"),
+            Ok(Command::Expression(Expression::Literal(SValue::new(
+                Value::Float(0.5)
+            ))))
+        );
+
+        assert_eq!(
+            pi_parser::command(".5"),
+            Ok(Command::Expression(Expression::Literal(SValue::new(
+                Value::Float(0.5)
+            ))))
+        );
+
+        assert_eq!(
+            pi_parser::command("2."),
+            Ok(Command::Expression(Expression::Literal(SValue::new(
+                Value::Float(2.0)
+            ))))
+        );
+
+        assert_eq!(
+            pi_parser::command("9223372036854775808"),
+            Ok(Command::Expression(Expression::Literal(SValue::new(
+                Value::BigInt("9223372036854775808".to_string())
+            ))))
+        );
+
         assert_eq!(
             pi_parser::command("\"hello\""),
             Ok(Command::Expression(Expression::Literal(SValue::new(
@@ -153,6 +418,22 @@ mod test {
             )))
         );
 
+        assert_eq!(
+            pi_parser::command("{ a: 1, \"b c\": 2, _d1:x }"),
+            Ok(Command::Expression(Expression::Dict(vec![
+                (
+                    "a".to_string(),
+                    Expression::Literal(SValue::new(Value::Int(1)))
+                ),
+                (
+                    "b c".to_string(),
+                    Expression::Literal(SValue::new(Value::Int(2)))
+                ),
+                ("_d1".to_string(), Expression::Identifier("x".to_string())),
+            ])))
+        );
+        assert!(pi_parser::command("{ true: 1 }").is_err());
+
         assert_eq!(
             pi_parser::command("%"),
             Ok(Command::Expression(Expression::This))
@@ -172,6 +453,17 @@ mod test {
             )))
         );
 
+        assert_eq!(
+            pi_parser::command("get x 1"),
+            Ok(Command::Expression(Expression::FunctionCall(
+                "get".to_string(),
+                vec![
+                    Expression::Identifier("x".to_string()),
+                    Expression::Literal(SValue::new(Value::Int(1))),
+                ]
+            )))
+        );
+
         assert_eq!(
             pi_parser::command("print"),
             Ok(Command::Expression(Expression::Identifier(
@@ -187,6 +479,27 @@ mod test {
             )))
         );
 
+        assert_eq!(
+            pi_parser::command("|a, b| a"),
+            Ok(Command::Expression(Expression::Lambda(
+                vec!["a".to_string(), "b".to_string()],
+                Box::new(Expression::Identifier("a".to_string()))
+            )))
+        );
+
+        assert_eq!(
+            pi_parser::command("[x + 1 for x in % if x]"),
+            Ok(Command::Expression(Expression::Comprehension {
+                body: Box::new(Expression::Plus(
+                    Box::new(Expression::Identifier("x".to_string())),
+                    Box::new(Expression::Literal(SValue::new(Value::Int(1))))
+                )),
+                variable: "x".to_string(),
+                iterable: Box::new(Expression::This),
+                condition: Some(Box::new(Expression::Identifier("x".to_string()))),
+            }))
+        );
+
         assert_eq!(pi_parser::command(">>"), Ok(Command::ShiftRight(None)));
 
         assert_eq!(
@@ -212,6 +525,36 @@ mod test {
             Ok(UserInput::Directive("print".to_string(), vec![]))
         );
 
+        assert_eq!(
+            pi_parser::user_input(".json-stream"),
+            Ok(UserInput::Directive("json-stream".to_string(), vec![]))
+        );
+
+        assert_eq!(
+            pi_parser::user_input(".quote \"a\" + b"),
+            Ok(UserInput::Command(Command::Expression(
+                Expression::Literal(SValue::new(Value::String("\"a\" + b".to_string())))
+            )))
+        );
+
+        assert_eq!(
+            pi_parser::user_input(".batch-apply get % 0"),
+            Ok(UserInput::BatchApply(Command::Expression(
+                Expression::FunctionCall(
+                    "get".to_string(),
+                    vec![
+                        Expression::This,
+                        Expression::Literal(SValue::new(Value::Int(0)))
+                    ]
+                )
+            )))
+        );
+
+        assert_eq!(
+            pi_parser::user_input(".q"),
+            Ok(UserInput::Directive("q".to_string(), vec![]))
+        );
+
         assert_eq!(
             pi_parser::user_input(".print 123"),
             Ok(UserInput::Directive(
@@ -220,4 +563,203 @@ mod test {
             ))
         );
     }
+
+    #[test]
+    fn test_precedence() {
+        let int = |n| Box::new(Expression::Literal(SValue::new(Value::Int(n))));
+        let expr = |e| Ok(Command::Expression(e));
+
+        assert_eq!(
+            pi_parser::command("1 + 2 * 3"),
+            expr(Expression::Plus(
+                int(1),
+                Box::new(Expression::Multiply(int(2), int(3)))
+            ))
+        );
+        assert_eq!(
+            pi_parser::command("(1 + 2) * 3"),
+            expr(Expression::Multiply(
+                Box::new(Expression::Plus(int(1), int(2))),
+                int(3)
+            ))
+        );
+        assert_eq!(
+            pi_parser::command("10 / 2 - 1"),
+            expr(Expression::Minus(
+                Box::new(Expression::Divide(int(10), int(2))),
+                int(1)
+            ))
+        );
+        assert_eq!(
+            pi_parser::command("1 + 7 %% 3 * 2"),
+            expr(Expression::Plus(
+                int(1),
+                Box::new(Expression::Multiply(
+                    Box::new(Expression::Modulo(int(7), int(3))),
+                    int(2)
+                ))
+            ))
+        );
+        assert_eq!(
+            pi_parser::command("% %% 2"),
+            expr(Expression::Modulo(Box::new(Expression::This), int(2)))
+        );
+        assert_eq!(
+            pi_parser::command("10 - 2 - 1"),
+            expr(Expression::Minus(
+                Box::new(Expression::Minus(int(10), int(2))),
+                int(1)
+            ))
+        );
+        assert_eq!(
+            pi_parser::command("get % 0 + 1"),
+            expr(Expression::Plus(
+                Box::new(Expression::FunctionCall(
+                    "get".to_string(),
+                    vec![Expression::This, *int(0)]
+                )),
+                int(1)
+            ))
+        );
+        assert_eq!(
+            pi_parser::command("1 or 2 and 3 + 4"),
+            expr(Expression::Or(
+                int(1),
+                Box::new(Expression::And(
+                    int(2),
+                    Box::new(Expression::Plus(int(3), int(4)))
+                ))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_call_arguments() {
+        let int = |n| Expression::Literal(SValue::new(Value::Int(n)));
+        let call = |name: &str, args| Expression::FunctionCall(name.to_string(), args);
+
+        // Arguments used to be whole expressions, these parsed as `get % (get [0] 0)` and
+        // `get % (0 + 1)`
+        assert_eq!(
+            pi_parser::command("get % get [0] 0"),
+            Ok(Command::Expression(call(
+                "get",
+                vec![
+                    Expression::This,
+                    Expression::Identifier("get".to_string()),
+                    Expression::List(vec![int(0)]),
+                    int(0),
+                ]
+            )))
+        );
+        assert_eq!(
+            pi_parser::command("get % (get [0] 0)"),
+            Ok(Command::Expression(call(
+                "get",
+                vec![
+                    Expression::This,
+                    call("get", vec![Expression::List(vec![int(0)]), int(0)]),
+                ]
+            )))
+        );
+        assert_eq!(
+            pi_parser::command("get % (0 + 1)"),
+            Ok(Command::Expression(call(
+                "get",
+                vec![
+                    Expression::This,
+                    Expression::Plus(Box::new(int(0)), Box::new(int(1))),
+                ]
+            )))
+        );
+    }
+
+    #[test]
+    fn test_unary_minus() {
+        let minus = |e| Ok(Command::Expression(Expression::UnaryMinus(Box::new(e))));
+        let int = |n| Expression::Literal(SValue::new(Value::Int(n)));
+
+        assert_eq!(pi_parser::command("-5"), minus(int(5)));
+        assert_eq!(pi_parser::command("-(1)"), minus(int(1)));
+        assert_eq!(pi_parser::command("- %"), minus(Expression::This));
+        assert_eq!(
+            pi_parser::command("- get 0"),
+            minus(Expression::FunctionCall("get".to_string(), vec![int(0)]))
+        );
+        assert_eq!(
+            pi_parser::command("1 - -2"),
+            Ok(Command::Expression(Expression::Minus(
+                Box::new(int(1)),
+                Box::new(Expression::UnaryMinus(Box::new(int(2))))
+            )))
+        );
+    }
+
+    #[test]
+    fn test_null_bool() {
+        for (source, value) in [
+            ("null", Value::Null),
+            ("true", Value::Bool(true)),
+            ("false", Value::Bool(false)),
+        ] {
+            assert_eq!(
+                pi_parser::command(source),
+                Ok(Command::Expression(Expression::Literal(SValue::new(value))))
+            );
+        }
+
+        assert_eq!(
+            pi_parser::command("nullable"),
+            Ok(Command::Expression(Expression::Identifier(
+                "nullable".to_string()
+            )))
+        );
+        assert_eq!(
+            pi_parser::command("truething false"),
+            Ok(Command::Expression(Expression::FunctionCall(
+                "truething".to_string(),
+                vec![Expression::Literal(SValue::new(Value::Bool(false)))]
+            )))
+        );
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let string = |s: &str| {
+            Ok(Command::Expression(Expression::Literal(SValue::new(
+                Value::String(s.to_string()),
+            ))))
+        };
+        assert_eq!(pi_parser::command(r#""a\tb""#), string("a\tb"));
+        assert_eq!(pi_parser::command(r#""quote:\"""#), string("quote:\""));
+        assert_eq!(pi_parser::command(r#""\u00e9\n\\""#), string("é\n\\"));
+        assert_eq!(pi_parser::command(r#""\ud83d\ude00""#), string("\u{1f600}"));
+        assert_eq!(pi_parser::command(r#""\u{1}\0\/""#), string("\u{1}\0/"));
+
+        for invalid in [r#""\q""#, r#""\u12""#, r#""\ud83d""#, r#""a\""#] {
+            assert!(pi_parser::command(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_display_roundtrip() {
+        for source in [
+            "|x, y| get x 0 + y * 2",
+            "[(get r \"name\") + \"!\" for r in % if r and json]",
+            "{\"a\": [1, %]} + -(1 / 2)",
+            "2.0 * .5 + 1",
+            "7 %% % - % %% 2",
+            r#""tab\t \"quoted\" \u{1} \\""#,
+            "% > 3 and % <= 4 or % != 1 == (2 >= 1 < 0)",
+            "-9223372036854775808 + 98765432109876543210",
+        ] {
+            let Ok(Command::Expression(e)) = pi_parser::command(source) else {
+                panic!("failed to parse {source}");
+            };
+            assert_eq!(
+                pi_parser::command(&e.to_string()),
+                Ok(Command::Expression(e))
+            );
+        }
+    }
 }
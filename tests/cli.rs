@@ -0,0 +1,113 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Run the binary on the arguments, feeding it the input lines, and return its stdout
+fn run_pi(args: &[&str], input: &str) -> String {
+    let output = spawn_pi(args, input);
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn spawn_pi(args: &[&str], input: &str) -> std::process::Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pi"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_input_format() {
+    let path = std::env::temp_dir().join(format!("pi-input-format-{}", std::process::id()));
+    std::fs::write(&path, "name,age\nalice,30\nbob,25\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    let raw = run_pi(&[path], ".d\n");
+    assert!(!raw.contains("$>"));
+    assert!(raw.ends_with("\"name,age\\nalice,30\\nbob,25\\n\"\n"));
+
+    let csv = run_pi(&["--input-format", "csv", path], ".d\n");
+    std::fs::remove_file(path).unwrap();
+    assert!(csv.ends_with("[{name: \"alice\", age: \"30\"}, {name: \"bob\", age: \"25\"}]\n"));
+}
+
+#[test]
+fn test_init() {
+    let path = std::env::temp_dir().join(format!("pi-init-{}", std::process::id()));
+    std::fs::write(&path, "# greetings\n.let greeting \"hi\"\nnot valid (\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    let output = run_pi(&["--init", path], "greeting\n.d\n");
+    std::fs::remove_file(path).unwrap();
+    assert!(output.ends_with("\"hi\"\n"));
+}
+
+#[test]
+fn test_piped_expression() {
+    let output = run_pi(&["-e", r#"get % "a""#], r#"{"a": [1, 2], "b": 3}"#);
+    assert_eq!(output, "[1, 2]\n");
+
+    let output = run_pi(
+        &["--input-format", "ndjson", "-e", "len", "-e", "% + 1"],
+        "{\"a\": 1}\n{\"a\": 2}\n",
+    );
+    assert_eq!(output, "3\n");
+
+    // Errors inside the lazy result fail the run too
+    let output = spawn_pi(&["-e", "filter |x| x"], "[true, 1]");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("Error: realizing the result\n  caused by: "));
+}
+
+#[test]
+fn test_shift_right_into_scalar() {
+    let output = spawn_pi(&["-e", ">>"], "5");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("Error: "));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn test_script_error() {
+    let output = spawn_pi(&[], "[1, 2]\n.json-stream\nnot valid (\n.d\n");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("1\n2\n"));
+    // The script stops at the error, before `.d`
+    assert!(!stdout.ends_with("[1, 2]\n"));
+}
+
+#[test]
+fn test_unknown_directive() {
+    let output = spawn_pi(&[], "[1, 2]\n.nope\n.d\n");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Unknown directive `nope`"));
+    assert!(!String::from_utf8(output.stdout)
+        .unwrap()
+        .ends_with("[1, 2]\n"));
+}
+
+#[test]
+fn test_batch_apply() {
+    let output = run_pi(
+        &[],
+        "[1, 2]\n.pin short\n[3, 4]\n.pin longer\n.batch-apply [x * 10 for x in %]\n\
+         .batch-apply get % 0\n",
+    );
+    assert_eq!(
+        output,
+        "short  | [10, 20]\nlonger | [30, 40]\nshort  | 1\nlonger | 3\n"
+    );
+}